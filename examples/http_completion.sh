@@ -20,3 +20,4 @@ curl http://127.0.0.1:8001/v1/code-completion -k \
 # Other possible parameters:
 # "scratchpad": "FIM-PSM",
 # "model": "smallcloudai/Refact-1_6b-fim",
+# "position_encoding": "utf-16",  (how cursor "character" is counted, default is unicode characters)
@@ -0,0 +1,47 @@
+# pip install pylspclient
+import pylspclient
+import socket
+import termcolor
+
+
+def on_chat_delta(params):
+    for choice in params["data"].get("choices", []):
+        print(termcolor.colored(choice["delta"]["content"], "magenta"), end="", flush=True)
+
+
+def main():
+    s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    s.connect(("127.0.0.1", 8002))
+    pipein, pipeout = s.makefile("wb", buffering=0), s.makefile("rb", buffering=0)
+    json_rpc_endpoint = pylspclient.JsonRpcEndpoint(pipein, pipeout)
+    lsp_endpoint = pylspclient.LspEndpoint(json_rpc_endpoint, notify_callbacks={"refact/chatDelta": on_chat_delta})
+    lsp_client = pylspclient.LspClient(lsp_endpoint)
+    capabilities = {}
+    root_uri = 'file:///workspace'
+    workspace_folders = [{'name': 'workspace', 'uri': root_uri}]
+    lsp_client.initialize(1337, None, root_uri, None, capabilities, "off", workspace_folders)
+
+    # Deltas arrive as refact/chatDelta notifications, the result is the whole message
+    result = lsp_client.lsp_endpoint.call_method(
+        "refact/chat",
+        chat_id="chat-1",
+        messages=[
+            {"role": "user", "content": "Who is Bill Clinton? What is his favorite programming language?"},
+        ],
+        parameters={
+            "temperature": 0.1,
+            "max_new_tokens": 300,
+        },
+    )
+    print()
+    print("refact/chat result:", result)
+
+    try:
+        lsp_client.shutdown()
+    except Exception:
+        pass
+    lsp_endpoint.join()
+
+
+if __name__ == "__main__":
+    main()
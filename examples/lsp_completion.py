@@ -30,7 +30,7 @@ def main():
         textDocument=pylspclient.lsp_structs.TextDocumentIdentifier(uri),
         position=pylspclient.lsp_structs.Position(1, 4),
         context = {
-            "triggerKind": "Invoke", #Completion activated by directly invoking it instead of an automatic trigger
+            "triggerKind": 1, # Invoked: completion activated by directly invoking it instead of an automatic trigger
             "selectedCompletionInfo": {
                 "range": pylspclient.lsp_structs.Range(pylspclient.lsp_structs.Position(1, 0), pylspclient.lsp_structs.Position(1, 3)),
                 "text": "    "
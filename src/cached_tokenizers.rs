@@ -7,7 +7,7 @@ use tokenizers::Tokenizer;
 use reqwest::header::AUTHORIZATION;
 use tracing::info;
 
-use crate::global_context::GlobalContext;
+use crate::global_context::{GlobalContext, StatusEvent};
 use crate::caps::CodeAssistantCaps;
 
 
@@ -55,6 +55,10 @@ async fn _download_tokenizer_file(
     Ok(())
 }
 
+pub fn tokenizer_cache_path(cache_dir: &Path, model_name: &str) -> std::path::PathBuf {
+    cache_dir.join("tokenizers").join(model_name).join("tokenizer.json")
+}
+
 pub async fn cached_tokenizer(
     caps: Arc<StdRwLock<CodeAssistantCaps>>,
     global_context: Arc<ARwLock<GlobalContext>>,
@@ -66,11 +70,7 @@ pub async fn cached_tokenizer(
     let tokenizer_arc = match cx_locked.tokenizer_map.get(&model_name) {
         Some(arc) => arc.clone(),
         None => {
-            let tokenizer_cache_dir = std::path::PathBuf::from(cache_dir).join("tokenizers");
-            tokio::fs::create_dir_all(&tokenizer_cache_dir)
-                .await
-                .expect("failed to create cache dir");
-            let path = tokenizer_cache_dir.join(model_name.clone()).join("tokenizer.json");
+            let path = tokenizer_cache_path(&cache_dir, &model_name);
             // Download it while it's locked, so another download won't start.
             let http_path;
             {
@@ -79,8 +79,29 @@ pub async fn cached_tokenizer(
                 let rewritten_model_name = caps_locked.tokenizer_rewrite_path.get(&model_name).unwrap_or(&model_name);
                 http_path = caps_locked.tokenizer_path_template.replace("$MODEL", rewritten_model_name);();
             }
-            _download_tokenizer_file(&client2, http_path.as_str(), cx_locked.cmdline.api_key.clone(), &path).await?;
+            let t0 = std::time::Instant::now();
+            let need_download = !path.exists();
+            let progress_token = format!("refact-tokenizer-{}", model_name);
+            if need_download {
+                let _ = cx_locked.status_events.send(StatusEvent::ProgressBegin {
+                    token: progress_token.clone(),
+                    title: format!("Downloading tokenizer for {}", model_name),
+                });
+            }
+            let download_result = _download_tokenizer_file(&client2, http_path.as_str(), cx_locked.cmdline.api_key.clone(), &path).await;
+            if need_download {
+                let _ = cx_locked.status_events.send(StatusEvent::ProgressEnd {
+                    token: progress_token,
+                    message: download_result.clone().err().unwrap_or("done".to_string()),
+                });
+            }
+            if let Err(e) = download_result {
+                cx_locked.status.last_error = format!("tokenizer for {}: {}", model_name, e);
+                crate::global_context::status_changed(&cx_locked);
+                return Err(e);
+            }
             let tokenizer = Tokenizer::from_file(path).map_err(|e| format!("failed to load tokenizer: {}", e))?;
+            crate::metrics::tokenizer_loaded(&cx_locked.metrics, &model_name, t0.elapsed());
             let arc = Arc::new(StdRwLock::new(tokenizer));
             cx_locked.tokenizer_map.insert(model_name.clone(), arc.clone());
             arc
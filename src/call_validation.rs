@@ -39,6 +39,8 @@ pub struct CodeCompletionPost {
     pub stream: bool,
     #[serde(default)]
     pub no_cache: bool,
+    #[serde(default)]
+    pub position_encoding: String,  // how cursor.character counts: "utf-8", "utf-16", "utf-32", empty means unicode chars
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -334,6 +334,11 @@ fn _inherit_r1_from_r0(
     }
 }
 
+pub fn code_completion_n_ctx(caps: &CodeAssistantCaps) -> usize {
+    // Completions are built with this, not the model's own n_ctx: a shorter prompt is faster
+    if caps.code_completion_n_ctx == 0 { 2048 } else { caps.code_completion_n_ctx }
+}
+
 pub fn which_model_to_use<'a>(
     models: &'a HashMap<String, ModelRecord>,
     user_wants_model: &str,
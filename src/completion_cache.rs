@@ -19,6 +19,7 @@ pub struct CompletionSaveToCache {
     pub completion0_finish_reason: String,
     pub completion0_snippet_telemetry_id: Option<u64>,
     pub model: String,
+    pub file: String,
 }
 
 impl CompletionSaveToCache {
@@ -33,6 +34,7 @@ impl CompletionSaveToCache {
             completion0_finish_reason: String::new(),
             completion0_snippet_telemetry_id: None,
             model: post.model.clone(),
+            file: post.inputs.cursor.file.clone(),
         }
     }
 }
@@ -42,12 +44,16 @@ impl CompletionSaveToCache {
 pub struct CompletionCache {
     pub map: HashMap<(String, String), serde_json::Value>,
     pub in_added_order: Vec<(String, String)>,
+    pub key_file: HashMap<(String, String), String>,  // the key is text before cursor, it doesn't have the file name
+    pub hits: u64,              // /v1/cache/stats and /metrics both read these
+    pub misses: u64,
+    pub prefill_entries: u64,   // inserted ahead of cursor, for when the user types what was suggested
 }
 
 impl CompletionCache {
     pub fn new(
     ) -> Self {
-        Self { map: HashMap::new(), in_added_order: Vec::new() }
+        Self { map: HashMap::new(), in_added_order: Vec::new(), key_file: HashMap::new(), hits: 0, misses: 0, prefill_entries: 0 }
     }
 }
 
@@ -55,30 +61,74 @@ pub fn cache_get(
     cache: Arc<StdRwLock<CompletionCache>>,
     key: (String, String),
 ) -> Option<serde_json::Value> {
-    let cache_locked = cache.write().unwrap();
-    if let Some(value) = cache_locked.map.get(&key) {
-        return Some(value.clone());
+    let mut cache_locked = cache.write().unwrap();
+    if let Some(value) = cache_locked.map.get(&key).cloned() {
+        cache_locked.hits += 1;
+        return Some(value);
     }
+    cache_locked.misses += 1;
     None
 }
 
+pub fn cache_stats(
+    cache: Arc<StdRwLock<CompletionCache>>,
+) -> serde_json::Value {
+    let cache_locked = cache.read().unwrap();
+    // Approximate: keys and values as serialized, not counting HashMap overhead
+    let bytes: usize = cache_locked.map.iter().map(|(k, v)| k.0.len() + k.1.len() + v.to_string().len()).sum();
+    serde_json::json!({
+        "entries": cache_locked.map.len(),
+        "max_entries": CACHE_ENTRIES,
+        "approx_bytes": bytes,
+        "hits": cache_locked.hits,
+        "misses": cache_locked.misses,
+        "prefill_entries": cache_locked.prefill_entries,
+    })
+}
+
+pub fn cache_clear(
+    cache: Arc<StdRwLock<CompletionCache>>,
+    file: Option<&str>,
+) -> usize {
+    // Hit and miss counters stay, they count since the start
+    let mut cache_locked = cache.write().unwrap();
+    let removed_keys: Vec<(String, String)> = match file {
+        Some(file) => cache_locked.key_file.iter().filter(|(_, f)| f.as_str() == file).map(|(k, _)| k.clone()).collect(),
+        None => cache_locked.map.keys().cloned().collect(),
+    };
+    for key in removed_keys.iter() {
+        cache_locked.map.remove(key);
+        cache_locked.key_file.remove(key);
+    }
+    let CompletionCache { map, in_added_order, .. } = &mut *cache_locked;
+    in_added_order.retain(|k| map.contains_key(k));
+    removed_keys.len()
+}
+
 pub fn cache_put(
     cache: Arc<StdRwLock<CompletionCache>>,
     new_key: (String, String),
+    file: &str,
     value: serde_json::Value,
-) {
+) -> bool {
     let mut cache_locked = cache.write().unwrap();
     while cache_locked.in_added_order.len() > CACHE_ENTRIES {
         let old_key = cache_locked.in_added_order.remove(0);
         cache_locked.map.remove(&old_key);
+        cache_locked.key_file.remove(&old_key);
     }
     // info!("cache put: {:?} = {:?}", new_key, value);
     let mut new_key_copy = new_key.clone();
-    if new_key_copy.0.len() > CACHE_KEY_CHARS {
-        new_key_copy.0 = new_key_copy.0[..CACHE_KEY_CHARS].to_string();
+    if new_key_copy.0.chars().count() > CACHE_KEY_CHARS {
+        new_key_copy.0 = new_key_copy.0.chars().take(CACHE_KEY_CHARS).collect();
+    }
+    if cache_locked.map.contains_key(&new_key_copy) {
+        return false;
     }
-    cache_locked.map.entry(new_key_copy.clone()).or_insert(value);
+    cache_locked.map.insert(new_key_copy.clone(), value);
+    cache_locked.key_file.insert(new_key_copy.clone(), file.to_string());
     cache_locked.in_added_order.push(new_key_copy.clone());
+    true
 }
 
 pub fn cache_key_from_post(
@@ -124,15 +174,19 @@ pub fn cache_key_from_post(
     key.push_str(&cursor_line.to_string());
     let chars = key.chars();
 
-    if chars.clone().count() > CACHE_KEY_CHARS {
-        key = chars.skip(key.len() - CACHE_KEY_CHARS).collect();
+    let chars_count = chars.clone().count();
+    if chars_count > CACHE_KEY_CHARS {
+        key = chars.skip(chars_count - CACHE_KEY_CHARS).collect();
     }
     return (key, cache_part2_from_post(post));
 }
 
 
 pub fn cache_part2_from_post(post: &CodeCompletionPost) -> String {
-    if post.inputs.multiline { "multiline".to_string() } else { "singleline".to_string() }
+    // Model and scratchpad are filled in by then, they can change at runtime (plugin settings), and an answer
+    // from another model must not come from cache
+    let lines = if post.inputs.multiline { "multiline" } else { "singleline" };
+    format!("{}/{}/{}", lines, post.model, post.scratchpad)
 }
 
 
@@ -157,7 +211,7 @@ impl Drop for CompletionSaveToCache {
                 self.cache_key.0.clone() + &self.completion0_text.chars().take(char_num).collect::<String>(),
                 self.cache_key.1.clone()
             );
-            cache_put(self.cache_arc.clone(), cache_key_ahead, serde_json::json!(
+            let inserted = cache_put(self.cache_arc.clone(), cache_key_ahead, &self.file, serde_json::json!(
                 {
                     "choices": [{
                         "index": 0,
@@ -169,6 +223,9 @@ impl Drop for CompletionSaveToCache {
                     "snippet_telemetry_id": self.completion0_snippet_telemetry_id,
                 }
             ));
+            if inserted && char_num > 0 {
+                self.cache_arc.write().unwrap().prefill_entries += 1;
+            }
         }
     }
 }
@@ -10,10 +10,13 @@ use structopt::StructOpt;
 use std::io::Write;
 use crate::caps::CodeAssistantCaps;
 use crate::completion_cache::CompletionCache;
+use crate::limits::Limits;
+use crate::metrics::Metrics;
 use crate::telemetry::telemetry_structs;
 use crate::vecdb_search::VecdbSearch;
 use crate::custom_error::ScratchError;
 use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
 
 
 #[derive(Debug, StructOpt, Clone)]
@@ -26,6 +29,20 @@ pub struct CommandLine {
     pub api_key: String,
     #[structopt(long, short="p", default_value="8001", help="Bind 127.0.0.1:<port> to listen for HTTP requests, such as /v1/code-completion, /v1/chat, /v1/caps.")]
     pub http_port: u16,
+    #[structopt(long, default_value="127.0.0.1", help="Address for the HTTP server, 0.0.0.0 makes it reachable from outside, for example from a container. Addresses other than loopback require --http-auth-token.")]
+    pub http_bind_address: String,
+    #[structopt(long, env="REFACT_HTTP_AUTH_TOKEN", default_value="", hide_env_values=true, help="If set, HTTP clients must send \"Authorization: Bearer <token>\".")]
+    pub http_auth_token: String,
+    #[structopt(long, default_value="", help="Comma-separated host names HTTP clients may use in Host and Origin headers, in addition to localhost and the bind address. Use * to allow any.")]
+    pub http_allowed_hosts: String,
+    #[structopt(long, help="If the HTTP port is busy, take any free port instead of exiting. Look up the actual port in ~/.cache/refact/http-servers/<pid>.json.")]
+    pub http_port_fallback: bool,
+    #[structopt(long, help="Also serve HTTP on a unix socket ~/.cache/refact/http-servers/<pid>.sock, the path is in <pid>.json next to it.")]
+    pub http_unix_socket: bool,
+    #[structopt(long, default_value="8", help="Requests running at the same time to one model, more get 429 Too Many Requests. 0 means no limit.")]
+    pub max_concurrent_per_model: usize,
+    #[structopt(long, default_value="", parse(try_from_str=crate::limits::parse_route_rates), help="Requests per second for HTTP routes, more get 429 Too Many Requests, for example \"/v1/code-completion=20,/v1/chat=1\".")]
+    pub http_rate_limits: HashMap<String, f64>,
     #[structopt(long, default_value="", help="End-user client version, such as version of VS Code plugin.")]
     pub enduser_client_version: String,
     #[structopt(long, short="b", help="Send basic telemetry (counters and errors)")]
@@ -36,9 +53,56 @@ pub struct CommandLine {
     pub lsp_port: u16,
     #[structopt(long, default_value="0", help="Act as an LSP server, use stdin stdout for communication. This is compatible with having an HTTP server at the same time. But it's not compatible with LSP port.")]
     pub lsp_stdin_stdout: u16,
+    #[structopt(long, default_value="100000000", help="Limit on the total size of documents the LSP server keeps in memory, the least recently changed are forgotten first. A forgotten document gets no completions until the IDE opens it again, its edits are ignored with a warning in the LSP log.")]
+    pub lsp_documents_max_bytes: usize,
+    #[structopt(long, default_value="2048", help="Other open documents are added to completion sources up to this many tokens (estimated), 0 turns it off.")]
+    pub lsp_open_tabs_tokens: usize,
 }
 
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RuntimeSettings {
+    // Sent by the IDE plugin at runtime (LSP workspace/didChangeConfiguration), empty values mean use the defaults
+    #[serde(default)]
+    pub code_completion_model: String,
+    #[serde(default)]
+    pub code_completion_scratchpad: String,
+    #[serde(default)]
+    pub code_completion_temperature: Option<f32>,
+    #[serde(default)]
+    pub code_completion_max_new_tokens: usize,
+    #[serde(default)]
+    pub chat_model: String,
+    #[serde(default)]
+    pub chat_scratchpad: String,
+    #[serde(default)]
+    pub chat_temperature: Option<f32>,
+    #[serde(default)]
+    pub chat_max_new_tokens: usize,
+    #[serde(default)]
+    pub basic_telemetry: Option<bool>,
+    #[serde(default)]
+    pub snippet_telemetry: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RefactStatus {
+    // Sent to LSP clients as refact/status, so plugins can show a status bar
+    pub caps: String,                  // "loading", "loaded", "error"
+    pub caps_version: i64,
+    pub model_reachable: Option<bool>, // unknown until the first model call
+    pub last_error: String,
+    #[serde(default)]
+    pub caps_error: String,            // the most recent caps load failure, stays after a successful reload
+}
+
+#[derive(Debug, Clone)]
+pub enum StatusEvent {
+    ProgressBegin { token: String, title: String },
+    ProgressEnd { token: String, message: String },
+    StatusChanged(RefactStatus),
+}
+
 // #[derive(Debug)]
 pub struct GlobalContext {
     pub http_client: reqwest::Client,
@@ -47,23 +111,86 @@ pub struct GlobalContext {
     pub tokenizer_map: HashMap< String, Arc<StdRwLock<Tokenizer>>>,
     pub caps: Option<Arc<StdRwLock<CodeAssistantCaps>>>,
     pub caps_last_attempted_ts: u64,
+    pub started: std::time::Instant,
+    pub lsp_clients_count: usize,
     pub cmdline: CommandLine,
+    pub settings: RuntimeSettings,
     pub completions_cache: Arc<StdRwLock<CompletionCache>>,
+    pub metrics: Arc<StdRwLock<Metrics>>,
+    pub limits: Arc<StdRwLock<Limits>>,
     pub telemetry: Arc<StdRwLock<telemetry_structs::Storage>>,
     pub vecdb_search: Arc<AMutex<Box<dyn VecdbSearch + Send>>>,
+    pub status: RefactStatus,
+    pub status_events: tokio::sync::broadcast::Sender<StatusEvent>,
+    pub endpoint_probe: Arc<AMutex<Option<(std::time::Instant, serde_json::Value)>>>,  // last /v1/status check of the model endpoint
 }
 
 pub type SharedGlobalContext = Arc<ARwLock<GlobalContext>>;
 const CAPS_RELOAD_BACKOFF: u64 = 60;       // seconds
 const CAPS_BACKGROUND_RELOAD: u64 = 3600;  // seconds
+const STATUS_EVENTS_CAPACITY: usize = 100;
+
+pub fn status_changed(cx: &GlobalContext) {
+    // No LSP clients connected is not an error
+    let _ = cx.status_events.send(StatusEvent::StatusChanged(cx.status.clone()));
+}
+
+fn status_caps_loading(cx: &mut GlobalContext) {
+    if cx.caps.is_none() && cx.status.caps != "loading" {
+        cx.status.caps = "loading".to_string();
+        status_changed(cx);
+    }
+}
+
+fn status_caps_result(cx: &mut GlobalContext, caps_result: &Result<Arc<StdRwLock<CodeAssistantCaps>>, String>) {
+    match caps_result {
+        Ok(caps) => {
+            cx.status.caps = "loaded".to_string();
+            cx.status.caps_version = caps.read().unwrap().caps_version;
+        },
+        Err(e) => {
+            if cx.caps.is_none() {
+                cx.status.caps = "error".to_string();
+            }
+            cx.status.last_error = format!("failed to load caps: {}", e);
+            cx.status.caps_error = e.clone();
+        }
+    }
+    status_changed(cx);
+}
+
+fn _status_model_call_changes(status: &RefactStatus, error_message: &Option<String>) -> bool {
+    status.model_reachable != Some(error_message.is_none()) || error_message.as_ref().is_some_and(|e| *e != status.last_error)
+}
+
+pub async fn status_model_call(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    error_message: Option<String>,
+) {
+    // Called after every model call, usually nothing changes, and then the write lock isn't needed
+    if !_status_model_call_changes(&global_context.read().await.status, &error_message) {
+        return;
+    }
+    let mut cx = global_context.write().await;
+    if !_status_model_call_changes(&cx.status, &error_message) {
+        return;
+    }
+    cx.status.model_reachable = Some(error_message.is_none());
+    if let Some(e) = error_message {
+        cx.status.last_error = e;
+    }
+    status_changed(&cx);
+}
 
 pub async fn caps_background_reload(
     global_context: Arc<ARwLock<GlobalContext>>,
 ) -> () {
     loop {
+        status_caps_loading(&mut *global_context.write().await);
         let caps_result = crate::caps::load_caps(
             CommandLine::from_args()
         ).await;
+        status_caps_result(&mut *global_context.write().await, &caps_result);
         match caps_result {
             Ok(caps) => {
                 let mut global_context_locked = global_context.write().await;
@@ -79,6 +206,25 @@ pub async fn caps_background_reload(
     }
 }
 
+pub async fn caps_reload_now(
+    global_context: Arc<ARwLock<GlobalContext>>,
+) -> Result<(Option<Arc<StdRwLock<CodeAssistantCaps>>>, Arc<StdRwLock<CodeAssistantCaps>>), String> {
+    // Requests in flight keep the old caps Arc they already have, new requests see the new one.
+    // If loading fails, the old caps stay.
+    status_caps_loading(&mut *global_context.write().await);
+    let caps_result = crate::caps::load_caps(
+        CommandLine::from_args()
+    ).await;
+    let mut global_context_locked = global_context.write().await;
+    global_context_locked.caps_last_attempted_ts = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    status_caps_result(&mut global_context_locked, &caps_result);
+    let caps = caps_result?;
+    let old_caps = global_context_locked.caps.replace(caps.clone());
+    info!("caps reload on request successful");
+    write!(std::io::stderr(), "CAPS\n").unwrap();
+    Ok((old_caps, caps))
+}
+
 pub async fn try_load_caps_quickly_if_not_present(
     global_context: Arc<ARwLock<GlobalContext>>,
 ) -> Result<Arc<StdRwLock<CodeAssistantCaps>>, ScratchError> {
@@ -94,12 +240,14 @@ pub async fn try_load_caps_quickly_if_not_present(
     if caps_last_attempted_ts + CAPS_RELOAD_BACKOFF > now {
         return Err(ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, "server is not reachable, no caps available".to_string()));
     }
+    status_caps_loading(&mut *global_context.write().await);
     let caps_result = crate::caps::load_caps(
         CommandLine::from_args()
     ).await;
     {
         let mut global_context_locked = global_context.write().await;
         global_context_locked.caps_last_attempted_ts = now;
+        status_caps_result(&mut global_context_locked, &caps_result);
         match caps_result {
             Ok(caps) => {
                 global_context_locked.caps = Some(caps.clone());
@@ -133,11 +281,28 @@ pub async fn look_for_piggyback_fields(
     }
 }
 
+pub async fn apply_runtime_settings(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    settings: RuntimeSettings,
+) {
+    let mut global_context_locked = global_context.write().await;
+    // Telemetry code reads the flags from the command line, override them there
+    if let Some(basic_telemetry) = settings.basic_telemetry {
+        global_context_locked.cmdline.basic_telemetry = basic_telemetry;
+    }
+    if let Some(snippet_telemetry) = settings.snippet_telemetry {
+        global_context_locked.cmdline.snippet_telemetry = snippet_telemetry;
+    }
+    info!("runtime settings {:?}", settings);
+    global_context_locked.settings = settings;
+}
+
 pub async fn create_global_context(
     cache_dir: PathBuf,
 ) -> (Arc<ARwLock<GlobalContext>>, std::sync::mpsc::Receiver<String>, CommandLine) {
     let cmdline = CommandLine::from_args();
     let (ask_shutdown_sender, ask_shutdown_receiver) = std::sync::mpsc::channel::<String>();
+    let (status_events, _) = tokio::sync::broadcast::channel::<StatusEvent>(STATUS_EVENTS_CAPACITY);
     let cx = GlobalContext {
         http_client: reqwest::Client::new(),
         ask_shutdown_sender: Arc::new(Mutex::new(ask_shutdown_sender)),
@@ -145,10 +310,18 @@ pub async fn create_global_context(
         tokenizer_map: HashMap::new(),
         caps: None,
         caps_last_attempted_ts: 0,
+        started: std::time::Instant::now(),
+        lsp_clients_count: 0,
         cmdline: cmdline.clone(),
+        settings: RuntimeSettings::default(),
         completions_cache: Arc::new(StdRwLock::new(CompletionCache::new())),
+        metrics: Arc::new(StdRwLock::new(Metrics::new())),
+        limits: Arc::new(StdRwLock::new(Limits::new(&cmdline))),
         telemetry: Arc::new(StdRwLock::new(telemetry_structs::Storage::new())),
         vecdb_search: Arc::new(AMutex::new(Box::new(crate::vecdb_search::VecdbSearchTest::new()))),
+        status: RefactStatus::default(),
+        status_events,
+        endpoint_probe: Arc::new(AMutex::new(None)),
     };
     (Arc::new(ARwLock::new(cx)), ask_shutdown_receiver, cmdline)
 }
@@ -1,27 +1,40 @@
-use axum::{Extension, http::{StatusCode, Uri}, response::IntoResponse, Router};
+use axum::{Extension, http::{StatusCode, Uri}, http::header::CONTENT_TYPE, response::IntoResponse, Router};
+use axum::routing::get;
 use tokio::signal;
-use tracing::info;
+use tracing::{error, info};
 
 use std::io::Write;
 use std::sync::Arc;
 use tokio::sync::RwLock as ARwLock;
 use hyper::Server;
 
-use crate::global_context::GlobalContext;
+use crate::global_context::{GlobalContext, SharedGlobalContext};
 // use crate::telemetry_snippets;
 use routers::make_v1_router;
 
 pub mod routers;
+mod auth;
+mod listeners;
 mod utils;
 
 async fn handler_404(path: Uri) -> impl IntoResponse {
     (StatusCode::NOT_FOUND, format!("no handler for {}", path))
 }
 
+async fn handler_metrics(Extension(global_context): Extension<SharedGlobalContext>) -> impl IntoResponse {
+    let (metrics, tele_storage, cache) = {
+        let cx_locked = global_context.read().await;
+        (cx_locked.metrics.clone(), cx_locked.telemetry.clone(), cx_locked.completions_cache.clone())
+    };
+    let text = crate::metrics::render(&metrics.read().unwrap(), &tele_storage.read().unwrap(), &cache.read().unwrap());
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
 
 pub fn make_server() -> Router {
     Router::new()
         .fallback(handler_404)
+        .route("/metrics", get(handler_metrics))
         .nest("/v1", make_v1_router())
 }
 
@@ -59,18 +72,71 @@ pub async fn start_server(
     global_context: Arc<ARwLock<GlobalContext>>,
     ask_shutdown_receiver: std::sync::mpsc::Receiver<String>
 ) -> Result<(), String> {
-    let port = global_context.read().await.cmdline.http_port;
-    let addr = ([127, 0, 0, 1], port).into();
-    let builder = Server::try_bind(&addr).map_err(|e| {
+    let cmdline = global_context.read().await.cmdline.clone();
+    let bind_address: std::net::IpAddr = cmdline.http_bind_address.parse().map_err(|e| {
+        format!("invalid --http-bind-address \"{}\": {}", cmdline.http_bind_address, e)
+    })?;
+    if !bind_address.is_loopback() && cmdline.http_auth_token.is_empty() {
+        // Anyone on the network could use the API key through /v1/chat
+        let e = format!("--http-bind-address {} is reachable from the network, set --http-auth-token too", bind_address);
+        write!(std::io::stderr(), "{}\n", e).unwrap();
+        std::io::stderr().flush().unwrap();
+        return Err(e);
+    }
+    let requested_addr = std::net::SocketAddr::new(bind_address, cmdline.http_port);
+    let incoming = listeners::bind_tcp(&requested_addr, cmdline.http_port_fallback).map_err(|e| {
         write!(std::io::stderr(), "PORT_BUSY {}\n", e).unwrap();
         std::io::stderr().flush().unwrap();
-        format!("port busy, address {}: {}", addr, e)
+        e
     })?;
+    let addr = incoming.local_addr();
     info!("HTTP server listening on {}", addr);
-    let router = make_server().layer(Extension(global_context.clone()));
-    let server = builder
+    let access = auth::HttpAccess::from_cmdline(&cmdline, &bind_address);
+    info!("HTTP bearer token {}, allowed hosts {:?}", if access.token.is_empty() { "not required" } else { "required" }, access.allowed_hosts);
+    let router = make_server()
+        .layer(axum::middleware::from_fn_with_state(Arc::new(access), auth::http_access_layer))
+        .layer(Extension(global_context.clone()));
+
+    let cache_dir = global_context.read().await.cache_dir.clone();
+    listeners::remove_stale(&cache_dir).await;
+    let mut cleanup_files = vec![];
+    let mut unix_socket_task: Option<tokio::task::JoinHandle<()>> = None;
+    let mut unix_socket_path: Option<String> = None;
+    if cmdline.http_unix_socket {
+        #[cfg(unix)]
+        match listeners::bind_unix_socket(&cache_dir).await {
+            Ok((path, listener)) => {
+                info!("HTTP server listening on unix socket {}", path.display());
+                unix_socket_task = Some(tokio::spawn(listeners::serve_unix_socket(listener, router.clone())));
+                unix_socket_path = Some(path.display().to_string());
+                cleanup_files.push(path);
+            },
+            Err(e) => error!("{}", e),
+        }
+        #[cfg(not(unix))]
+        error!("--http-unix-socket is not supported on this platform");
+    }
+    let discovery = listeners::Discovery {
+        pid: std::process::id(),
+        http_address: addr.to_string(),
+        http_port: addr.port(),
+        unix_socket: unix_socket_path,
+    };
+    match listeners::write_discovery(&cache_dir, &discovery).await {
+        Ok(path) => {
+            info!("addresses written to {}", path.display());
+            cleanup_files.push(path);
+        },
+        Err(e) => error!("{}", e),
+    }
+
+    let server = Server::builder(incoming)
         .serve(router.into_make_service())
         .with_graceful_shutdown(shutdown_signal(ask_shutdown_receiver));
     let resp = server.await.map_err(|e| format!("HTTP server error: {}", e));
+    if let Some(task) = unix_socket_task {
+        task.abort();
+    }
+    listeners::remove_files(cleanup_files).await;
     resp
 }
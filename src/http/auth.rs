@@ -0,0 +1,199 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::middleware::Next;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::{AUTHORIZATION, HOST, ORIGIN};
+use tracing::info;
+
+use crate::custom_error::ScratchError;
+use crate::global_context::CommandLine;
+
+// Any local process or web page can reach 127.0.0.1. A page can also point its own domain to 127.0.0.1
+// (DNS rebinding), then the browser sends Host: evil.com. So Host and Origin must be ours, and if there's
+// a token, the client must know it.
+
+
+#[derive(Debug, Clone)]
+pub struct HttpAccess {
+    pub token: String,               // empty means no bearer token required
+    pub allowed_hosts: Vec<String>,  // lowercase, IPv6 in brackets, without port
+    pub any_host: bool,
+}
+
+impl HttpAccess {
+    pub fn from_cmdline(cmdline: &CommandLine, bind_address: &IpAddr) -> Self {
+        let mut allowed_hosts: Vec<String> = vec!["localhost".to_string(), "127.0.0.1".to_string(), "[::1]".to_string()];
+        let mut extra_hosts: Vec<String> = cmdline.http_allowed_hosts.split(',').map(|h| h.trim().to_lowercase()).filter(|h| !h.is_empty()).collect();
+        if !bind_address.is_unspecified() {
+            extra_hosts.push(match bind_address {
+                IpAddr::V4(a) => a.to_string(),
+                IpAddr::V6(a) => format!("[{}]", a),
+            });
+        }
+        let any_host = extra_hosts.iter().any(|h| h == "*");
+        for host in extra_hosts {
+            if !allowed_hosts.contains(&host) {
+                allowed_hosts.push(host);
+            }
+        }
+        HttpAccess {
+            token: cmdline.http_auth_token.clone(),
+            allowed_hosts,
+            any_host,
+        }
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        self.any_host || self.allowed_hosts.iter().any(|h| h == &host.to_lowercase())
+    }
+}
+
+fn _host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map(|pos| &host[..=pos]).unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+fn _same_token(a: &str, b: &str) -> bool {
+    // Doesn't stop at the first difference, so response time says nothing about the token
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn _check(access: &HttpAccess, request: &Request<Body>) -> Result<(), ScratchError> {
+    let header_str = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+    let host = header_str(HOST).map(_host_without_port).unwrap_or("");
+    if !access.host_allowed(host) {
+        return Err(ScratchError::new(StatusCode::FORBIDDEN, format!("host \"{}\" is not allowed, see --http-allowed-hosts", host)));
+    }
+    // IDE plugins don't send Origin, browsers do, and a web page can't fake it
+    if let Some(origin) = header_str(ORIGIN) {
+        let origin_host = url::Url::parse(origin).ok()
+            .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+            .map(|u| u.host_str().unwrap_or("").to_string());
+        let web_origin = origin == "null" || origin_host.is_some();
+        if web_origin && !origin_host.is_some_and(|h| access.host_allowed(&h)) {
+            return Err(ScratchError::new(StatusCode::FORBIDDEN, format!("origin \"{}\" is not allowed", origin)));
+        }
+    }
+    if !access.token.is_empty() {
+        let bearer = header_str(AUTHORIZATION).and_then(|v| v.strip_prefix("Bearer ")).unwrap_or("");
+        if !_same_token(bearer, &access.token) {
+            return Err(ScratchError::new(StatusCode::UNAUTHORIZED, "missing or wrong bearer token".to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub async fn http_access_layer(
+    State(access): State<Arc<HttpAccess>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response<axum::body::BoxBody> {
+    if let Err(e) = _check(&access, &request) {
+        info!("{} {} rejected", request.method(), request.uri());
+        let (parts, body) = e.to_response().into_parts();
+        return Response::from_parts(parts, axum::body::boxed(body));
+    }
+    next.run(request).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+    use super::*;
+
+    fn access(token: &str, extra_hosts: &str) -> HttpAccess {
+        let cmdline = CommandLine::from_iter(["refact-lsp", "--address-url", "Refact", "--http-auth-token", token, "--http-allowed-hosts", extra_hosts]);
+        HttpAccess::from_cmdline(&cmdline, &"127.0.0.1".parse().unwrap())
+    }
+
+    fn status(access: &HttpAccess, headers: &[(&str, &str)]) -> u16 {
+        let mut request = Request::builder().uri("/v1/caps");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        match _check(access, &request.body(Body::empty()).unwrap()) {
+            Ok(()) => 200,
+            Err(e) => e.status_code.as_u16(),
+        }
+    }
+
+    #[test]
+    fn test_host_without_port() {
+        assert_eq!(_host_without_port("localhost:8001"), "localhost");
+        assert_eq!(_host_without_port("localhost"), "localhost");
+        assert_eq!(_host_without_port("[::1]:8001"), "[::1]");
+        assert_eq!(_host_without_port("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn test_from_cmdline() {
+        let a = access("", " Example.com ,,");
+        assert_eq!(a.allowed_hosts, vec!["localhost", "127.0.0.1", "[::1]", "example.com"]);
+        assert!(!a.any_host);
+        let cmdline = CommandLine::from_iter(["refact-lsp", "--address-url", "Refact"]);
+        assert_eq!(HttpAccess::from_cmdline(&cmdline, &"0.0.0.0".parse().unwrap()).allowed_hosts.len(), 3);
+        assert!(HttpAccess::from_cmdline(&cmdline, &"::2".parse().unwrap()).allowed_hosts.contains(&"[::2]".to_string()));
+    }
+
+    #[test]
+    fn test_host() {
+        let a = access("", "example.com");
+        assert_eq!(status(&a, &[("host", "127.0.0.1:8001")]), 200);
+        assert_eq!(status(&a, &[("host", "[::1]:8001")]), 200);
+        assert_eq!(status(&a, &[("host", "LOCALHOST:8001")]), 200);
+        assert_eq!(status(&a, &[("host", "Example.COM")]), 200);
+        assert_eq!(status(&a, &[("host", "evil.com:8001")]), 403);
+        assert_eq!(status(&a, &[("host", "localhost.evil.com")]), 403);
+        assert_eq!(status(&a, &[]), 403);
+    }
+
+    #[test]
+    fn test_origin() {
+        let a = access("", "");
+        let host = ("host", "localhost:8001");
+        assert_eq!(status(&a, &[host, ("origin", "http://localhost:3000")]), 200);
+        assert_eq!(status(&a, &[host, ("origin", "https://127.0.0.1")]), 200);
+        assert_eq!(status(&a, &[host, ("origin", "http://evil.com")]), 403);
+        // Sandboxed iframes and file:// pages send "null"
+        assert_eq!(status(&a, &[host, ("origin", "null")]), 403);
+        // IDE webviews aren't web pages
+        assert_eq!(status(&a, &[host, ("origin", "vscode-webview://abc123")]), 200);
+    }
+
+    #[test]
+    fn test_any_host() {
+        let a = access("", "*");
+        assert!(a.any_host);
+        assert_eq!(status(&a, &[("host", "evil.com")]), 200);
+        assert_eq!(status(&a, &[]), 200);
+        assert_eq!(status(&a, &[("host", "evil.com"), ("origin", "http://evil.com")]), 200);
+    }
+
+    #[test]
+    fn test_token() {
+        let a = access("s3cret", "");
+        let host = ("host", "localhost:8001");
+        assert_eq!(status(&a, &[host, ("authorization", "Bearer s3cret")]), 200);
+        assert_eq!(status(&a, &[host]), 401);
+        assert_eq!(status(&a, &[host, ("authorization", "Bearer")]), 401);
+        assert_eq!(status(&a, &[host, ("authorization", "Bearer wrong")]), 401);
+        assert_eq!(status(&a, &[host, ("authorization", "Bearer s3creT")]), 401);
+        assert_eq!(status(&a, &[host, ("authorization", "Bearer s3cret2")]), 401);
+        assert_eq!(status(&a, &[host, ("authorization", "s3cret")]), 401);
+        // Host is checked first, the token doesn't help a rebinding page
+        assert_eq!(status(&a, &[("host", "evil.com"), ("authorization", "Bearer s3cret")]), 403);
+    }
+
+    #[test]
+    fn test_same_token() {
+        assert!(_same_token("abc", "abc"));
+        assert!(!_same_token("abc", "abd"));
+        assert!(!_same_token("abc", "abcd"));
+        assert!(!_same_token("", "abc"));
+    }
+}
@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use axum::Router;
+use hyper::Server;
+use hyper::server::conn::AddrIncoming;
+use serde::Serialize;
+use tracing::{info, warn};
+
+// Plugins start one binary per IDE window, so ports collide. If --http-port-fallback is set, a busy port
+// is replaced by any free one. Either way, the actual addresses are written to
+// ~/.cache/refact/http-servers/<pid>.json, the plugin knows the pid of the process it started.
+
+
+#[derive(Debug, Serialize)]
+pub struct Discovery {
+    pub pid: u32,
+    pub http_address: String,
+    pub http_port: u16,
+    pub unix_socket: Option<String>,
+}
+
+pub fn servers_dir(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("http-servers")
+}
+
+pub fn bind_tcp(addr: &SocketAddr, port_fallback: bool) -> Result<AddrIncoming, String> {
+    match AddrIncoming::bind(addr) {
+        Ok(incoming) => Ok(incoming),
+        Err(e) if port_fallback => {
+            warn!("port busy, address {}: {}, will use any free port", addr, e);
+            AddrIncoming::bind(&SocketAddr::new(addr.ip(), 0)).map_err(|e| format!("cannot bind {}: {}", addr.ip(), e))
+        },
+        Err(e) => Err(format!("port busy, address {}: {}", addr, e)),
+    }
+}
+
+pub async fn write_discovery(cache_dir: &Path, discovery: &Discovery) -> Result<PathBuf, String> {
+    let dir = servers_dir(cache_dir);
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let path = dir.join(format!("{}.json", discovery.pid));
+    // Write and rename, so a plugin never reads half a file
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_string_pretty(discovery).unwrap()).await
+        .map_err(|e| format!("cannot write {}: {}", tmp_path.display(), e))?;
+    tokio::fs::rename(&tmp_path, &path).await.map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+#[cfg(unix)]
+fn _pid_running(pid: u32) -> bool {
+    // Signal 0 checks the process exists without touching it, EPERM means it's someone else's, but alive
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn _pid_running(_pid: u32) -> bool {
+    true
+}
+
+pub async fn remove_stale(cache_dir: &Path) {
+    // A killed process can't clean up after itself, its files would point plugins to nothing
+    let mut entries = match tokio::fs::read_dir(servers_dir(cache_dir)).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let my_pid = std::process::id();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let (pid_str, ext) = match file_name.split_once('.') {
+            Some(x) => x,
+            None => continue,
+        };
+        if !["json", "json.tmp", "sock"].contains(&ext) {
+            continue;
+        }
+        let pid = match pid_str.parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        if pid != my_pid && !_pid_running(pid) {
+            info!("removing {}, process {} is not running", entry.path().display(), pid);
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+pub async fn remove_files(paths: Vec<PathBuf>) {
+    for path in paths {
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+}
+
+#[cfg(unix)]
+pub async fn bind_unix_socket(cache_dir: &Path) -> Result<(PathBuf, tokio::net::UnixListener), String> {
+    let dir = servers_dir(cache_dir);
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let path = dir.join(format!("{}.sock", std::process::id()));
+    // Left over from a crashed process with the same pid
+    let _ = tokio::fs::remove_file(&path).await;
+    let listener = tokio::net::UnixListener::bind(&path).map_err(|e| format!("cannot bind {}: {}", path.display(), e))?;
+    Ok((path, listener))
+}
+
+#[cfg(unix)]
+pub async fn serve_unix_socket(listener: tokio::net::UnixListener, router: Router) {
+    let incoming = hyper::server::accept::poll_fn(move |cx| {
+        listener.poll_accept(cx).map(|res| Some(res.map(|(stream, _)| stream)))
+    });
+    if let Err(e) = Server::builder(incoming).serve(router.into_make_service()).await {
+        warn!("unix socket server error: {}", e);
+    }
+    info!("unix socket server stopped");
+}
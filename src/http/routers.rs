@@ -3,18 +3,25 @@ use std::pin::Pin;
 
 use axum::Extension;
 use axum::Router;
+use axum::middleware::from_fn;
 use axum::routing::{get, post};
 use hyper::{Body, Response};
 
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
-use crate::http::routers::v1::caps::handle_v1_caps;
-use crate::http::routers::v1::chat::handle_v1_chat;
+use crate::http::routers::v1::cache::{handle_v1_cache_clear, handle_v1_cache_stats};
+use crate::http::routers::v1::caps::{handle_v1_caps, handle_v1_caps_reload};
+use crate::http::routers::v1::chat::handle_v1_chat_web;
 use crate::http::routers::v1::code_completion::handle_v1_code_completion_web;
 use crate::http::routers::v1::graceful_shutdown::handle_v1_graceful_shutdown;
+use crate::http::routers::v1::models::handle_v1_models;
+use crate::http::routers::v1::prompt_preview::handle_v1_prompt_preview;
+use crate::http::routers::v1::openai_compat::{handle_v1_openai_chat_completions, handle_v1_openai_completions};
 use crate::http::routers::v1::snippet_accepted::handle_v1_snippet_accepted;
+use crate::http::routers::v1::status::handle_v1_status;
 use crate::http::routers::v1::telemetry_network::handle_v1_telemetry_network;
-use crate::http::utils::telemetry_wrapper;
+use crate::http::routers::v1::tokenize::{handle_v1_count_tokens, handle_v1_detokenize, handle_v1_tokenize};
+use crate::http::utils::{rate_limit_layer, telemetry_wrapper};
 use crate::telemetry_get;
 use crate::telemetry_post;
 
@@ -24,10 +31,22 @@ pub mod v1;
 pub fn make_v1_router() -> Router {
     Router::new()
         .route("/code-completion", telemetry_post!(handle_v1_code_completion_web))
-        .route("/chat", telemetry_post!(handle_v1_chat))
+        .route("/chat", telemetry_post!(handle_v1_chat_web))
         .route("/telemetry-network", telemetry_post!(handle_v1_telemetry_network))
         .route("/snippet-accepted", telemetry_post!(handle_v1_snippet_accepted))
+        .route("/completions", telemetry_post!(handle_v1_openai_completions))
+        .route("/chat/completions", telemetry_post!(handle_v1_openai_chat_completions))
+        .route("/prompt-preview", telemetry_post!(handle_v1_prompt_preview))
+        .route("/tokenize", telemetry_post!(handle_v1_tokenize))
+        .route("/detokenize", telemetry_post!(handle_v1_detokenize))
+        .route("/count-tokens", telemetry_post!(handle_v1_count_tokens))
+        .route("/caps-reload", telemetry_post!(handle_v1_caps_reload))
+        .route("/cache/clear", telemetry_post!(handle_v1_cache_clear))
 
         .route("/caps", telemetry_get!(handle_v1_caps))
         .route("/graceful-shutdown", telemetry_get!(handle_v1_graceful_shutdown))
+        .route("/status", telemetry_get!(handle_v1_status))
+        .route("/models", telemetry_get!(handle_v1_models))
+        .route("/cache/stats", telemetry_get!(handle_v1_cache_stats))
+        .layer(from_fn(rate_limit_layer))
 }
\ No newline at end of file
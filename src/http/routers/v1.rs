@@ -3,4 +3,10 @@ pub mod chat;
 pub mod telemetry_network;
 pub mod snippet_accepted;
 pub mod caps;
-pub mod graceful_shutdown;
\ No newline at end of file
+pub mod graceful_shutdown;
+pub mod openai_compat;
+pub mod prompt_preview;
+pub mod tokenize;
+pub mod status;
+pub mod models;
+pub mod cache;
\ No newline at end of file
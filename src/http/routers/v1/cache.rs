@@ -0,0 +1,49 @@
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::completion_cache;
+use crate::custom_error::ScratchError;
+use crate::global_context::SharedGlobalContext;
+
+
+#[derive(Debug, Deserialize, Default)]
+struct CacheClearPost {
+    #[serde(default)]
+    file: Option<String>,   // same name as in "cursor" of /v1/code-completion, absent means everything
+}
+
+pub async fn handle_v1_cache_stats(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    _: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let cache_arc = global_context.read().await.completions_cache.clone();
+    let stats = completion_cache::cache_stats(cache_arc);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&stats).unwrap()))
+        .unwrap())
+}
+
+pub async fn handle_v1_cache_clear(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = if body_bytes.is_empty() {
+        CacheClearPost::default()
+    } else {
+        serde_json::from_slice::<CacheClearPost>(&body_bytes).map_err(|e|
+            ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+        )?
+    };
+    let cache_arc = global_context.read().await.completions_cache.clone();
+    let removed = completion_cache::cache_clear(cache_arc, post.file.as_deref());
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&json!({"removed": removed})).unwrap()))
+        .unwrap())
+}
@@ -1,8 +1,11 @@
+use std::collections::HashMap;
+
 use axum::Extension;
 use axum::response::Result;
 use hyper::{Body, Response, StatusCode};
 use serde_json::json;
 
+use crate::caps::ModelRecord;
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
 
@@ -25,3 +28,34 @@ pub async fn handle_v1_caps(
         .unwrap();
     Ok(response)
 }
+
+fn _models_diff(old: Option<&HashMap<String, ModelRecord>>, new: &HashMap<String, ModelRecord>) -> serde_json::Value {
+    let mut added: Vec<&String> = new.keys().filter(|k| !old.is_some_and(|old| old.contains_key(*k))).collect();
+    let mut removed: Vec<&String> = old.map(|old| old.keys().filter(|k| !new.contains_key(*k)).collect()).unwrap_or_default();
+    added.sort();
+    removed.sort();
+    json!({"added": added, "removed": removed})
+}
+
+pub async fn handle_v1_caps_reload(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    _: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let (old_caps, new_caps) = crate::global_context::caps_reload_now(global_context.clone()).await.map_err(|e|
+        ScratchError::new(StatusCode::SERVICE_UNAVAILABLE, format!("failed to reload caps: {}", e))
+    )?;
+    let old_caps_locked = old_caps.as_ref().map(|caps| caps.read().unwrap());
+    let new_caps_locked = new_caps.read().unwrap();
+    let body = json!({
+        "caps_version": {
+            "old": old_caps_locked.as_ref().map(|caps| caps.caps_version),
+            "new": new_caps_locked.caps_version,
+        },
+        "code_completion_models": _models_diff(old_caps_locked.as_ref().map(|caps| &caps.code_completion_models), &new_caps_locked.code_completion_models),
+        "code_chat_models": _models_diff(old_caps_locked.as_ref().map(|caps| &caps.code_chat_models), &new_caps_locked.code_chat_models),
+    });
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&body).unwrap()))
+        .unwrap())
+}
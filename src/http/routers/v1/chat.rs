@@ -11,6 +11,7 @@ use crate::caps;
 use crate::caps::CodeAssistantCaps;
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
+use crate::scratchpad_abstract::ScratchpadAbstract;
 use crate::scratchpads;
 
 async fn _lookup_chat_scratchpad(
@@ -32,37 +33,52 @@ async fn _lookup_chat_scratchpad(
     Ok((model_name, sname.clone(), patch.clone()))
 }
 
-pub async fn handle_v1_chat(
-    Extension(global_context): Extension<SharedGlobalContext>,
-    body_bytes: hyper::body::Bytes,
-) -> Result<Response<Body>, ScratchError> {
-    let mut chat_post = serde_json::from_slice::<ChatPost>(&body_bytes).map_err(|e|
-        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
-    )?;
+pub async fn chat_post_validate_and_patch(
+    global_context: SharedGlobalContext,
+    chat_post: &mut ChatPost,
+) -> Result<(Arc<StdRwLock<CodeAssistantCaps>>, String, String, serde_json::Value), ScratchError> {
+    // Fills model, scratchpad and sampling defaults into the post, returns what the scratchpad needs
+    let settings = global_context.read().await.settings.clone();
+    if chat_post.model.is_empty() {
+        chat_post.model = settings.chat_model.clone();
+    }
+    if chat_post.scratchpad.is_empty() {
+        chat_post.scratchpad = settings.chat_scratchpad.clone();
+    }
     let caps = crate::global_context::try_load_caps_quickly_if_not_present(global_context.clone()).await?;
     let (model_name, scratchpad_name, scratchpad_patch) = _lookup_chat_scratchpad(
         caps.clone(),
-        &chat_post,
+        chat_post,
     ).await.map_err(|e| {
         ScratchError::new(StatusCode::BAD_REQUEST, format!("{}", e))
     })?;
+    if chat_post.parameters.max_new_tokens == 0 {
+        chat_post.parameters.max_new_tokens = settings.chat_max_new_tokens;
+    }
     if chat_post.parameters.max_new_tokens == 0 {
         chat_post.parameters.max_new_tokens = 2048;
     }
-    chat_post.parameters.temperature = Some(chat_post.parameters.temperature.unwrap_or(0.2));
+    chat_post.parameters.temperature = Some(chat_post.parameters.temperature.or(settings.chat_temperature).unwrap_or(0.2));
     chat_post.model = model_name.clone();
-    let (client1, api_key) = {
-        let cx_locked = global_context.write().await;
-        (cx_locked.http_client.clone(), cx_locked.cmdline.api_key.clone())
-    };
+    Ok((caps, model_name, scratchpad_name, scratchpad_patch))
+}
+
+pub async fn chat_scratchpad_and_prompt(
+    global_context: SharedGlobalContext,
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    model_name: &str,
+    chat_post: &mut ChatPost,
+    scratchpad_name: &str,
+    scratchpad_patch: &serde_json::Value,
+) -> Result<(Box<dyn ScratchpadAbstract>, String), ScratchError> {
     let vecdb_search = global_context.read().await.vecdb_search.clone();
     let mut scratchpad = scratchpads::create_chat_scratchpad(
         global_context.clone(),
         caps,
-        model_name.clone(),
+        model_name.to_string(),
         chat_post.clone(),
-        &scratchpad_name,
-        &scratchpad_patch,
+        scratchpad_name,
+        scratchpad_patch,
         vecdb_search,
     ).await.map_err(|e|
         ScratchError::new(StatusCode::BAD_REQUEST, e)
@@ -76,6 +92,27 @@ pub async fn handle_v1_chat(
     )?;
     // info!("chat prompt {:?}\n{}", t1.elapsed(), prompt);
     info!("chat prompt {:?}", t1.elapsed());
+    Ok((scratchpad, prompt))
+}
+
+pub async fn handle_v1_chat(
+    global_context: SharedGlobalContext,
+    chat_post: &mut ChatPost,
+) -> Result<Response<Body>, ScratchError> {
+    let (caps, model_name, scratchpad_name, scratchpad_patch) =
+        chat_post_validate_and_patch(global_context.clone(), chat_post).await?;
+    let (client1, api_key) = {
+        let cx_locked = global_context.write().await;
+        (cx_locked.http_client.clone(), cx_locked.cmdline.api_key.clone())
+    };
+    let (scratchpad, prompt) = chat_scratchpad_and_prompt(
+        global_context.clone(),
+        caps,
+        &model_name,
+        chat_post,
+        &scratchpad_name,
+        &scratchpad_patch,
+    ).await?;
     crate::restream::scratchpad_interaction_stream(
         global_context.clone(),
         scratchpad,
@@ -86,4 +123,14 @@ pub async fn handle_v1_chat(
         api_key,
         chat_post.parameters.clone(),
     ).await
+}
+
+pub async fn handle_v1_chat_web(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let mut chat_post = serde_json::from_slice::<ChatPost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    handle_v1_chat(global_context.clone(), &mut chat_post).await
 }
\ No newline at end of file
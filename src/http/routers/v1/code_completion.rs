@@ -12,6 +12,8 @@ use crate::caps::CodeAssistantCaps;
 use crate::completion_cache;
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
+use crate::position_encoding;
+use crate::scratchpad_abstract::ScratchpadAbstract;
 use crate::scratchpads;
 
 async fn _lookup_code_completion_scratchpad(
@@ -30,15 +32,22 @@ async fn _lookup_code_completion_scratchpad(
         &code_completion_post.scratchpad,
         &recommended_model_record.default_scratchpad,
     )?;
-    let mut n_ctx = caps_locked.code_completion_n_ctx;
-    if n_ctx == 0 { n_ctx = 2048 }
+    let n_ctx = crate::caps::code_completion_n_ctx(&caps_locked);
     Ok((model_name, sname.clone(), patch.clone(), n_ctx))
 }
 
-pub async fn handle_v1_code_completion(
+pub async fn code_completion_post_validate_and_patch(
     global_context: SharedGlobalContext,
     code_completion_post: &mut CodeCompletionPost,
-) -> Result<Response<Body>, ScratchError> {
+) -> Result<(Arc<StdRwLock<CodeAssistantCaps>>, String, String, serde_json::Value, usize), ScratchError> {
+    // Fills model, scratchpad and sampling defaults into the post, returns what the scratchpad needs
+    let settings = global_context.read().await.settings.clone();
+    if code_completion_post.model.is_empty() {
+        code_completion_post.model = settings.code_completion_model.clone();
+    }
+    if code_completion_post.scratchpad.is_empty() {
+        code_completion_post.scratchpad = settings.code_completion_scratchpad.clone();
+    }
     let caps = crate::global_context::try_load_caps_quickly_if_not_present(global_context.clone()).await?;
     let (model_name, scratchpad_name, scratchpad_patch, n_ctx) = _lookup_code_completion_scratchpad(
         caps.clone(),
@@ -46,6 +55,9 @@ pub async fn handle_v1_code_completion(
     ).await.map_err(|e| {
         ScratchError::new(StatusCode::BAD_REQUEST, format!("{}", e))
     })?;
+    if code_completion_post.parameters.max_new_tokens == 0 {
+        code_completion_post.parameters.max_new_tokens = settings.code_completion_max_new_tokens;
+    }
     if code_completion_post.parameters.max_new_tokens == 0 {
         code_completion_post.parameters.max_new_tokens = 50;
     }
@@ -55,10 +67,56 @@ pub async fn handle_v1_code_completion(
     if code_completion_post.scratchpad == "" {
         code_completion_post.scratchpad = scratchpad_name.clone();
     }
-    code_completion_post.parameters.temperature = Some(code_completion_post.parameters.temperature.unwrap_or(0.2));
-    let (client1, api_key, cache_arc, tele_storage) = {
+    code_completion_post.parameters.temperature = Some(code_completion_post.parameters.temperature.or(settings.code_completion_temperature).unwrap_or(0.2));
+    Ok((caps, model_name, scratchpad_name, scratchpad_patch, n_ctx))
+}
+
+pub async fn code_completion_scratchpad_and_prompt(
+    global_context: SharedGlobalContext,
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    model_name: &str,
+    code_completion_post: &mut CodeCompletionPost,
+    scratchpad_name: &str,
+    scratchpad_patch: &serde_json::Value,
+    n_ctx: usize,
+) -> Result<(Box<dyn ScratchpadAbstract>, String), ScratchError> {
+    let (cache_arc, tele_storage) = {
+        let cx_locked = global_context.read().await;
+        (cx_locked.completions_cache.clone(), cx_locked.telemetry.clone())
+    };
+    let mut scratchpad = scratchpads::create_code_completion_scratchpad(
+        global_context.clone(),
+        caps,
+        model_name.to_string(),
+        code_completion_post.clone(),
+        scratchpad_name,
+        scratchpad_patch,
+        cache_arc,
+        tele_storage,
+    ).await.map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, e)
+    )?;
+    let t1 = std::time::Instant::now();
+    let prompt = scratchpad.prompt(
+        n_ctx,
+        &mut code_completion_post.parameters,
+    ).await.map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Prompt: {}", e))
+    )?;
+    // info!("prompt {:?}\n{}", t1.elapsed(), prompt);
+    info!("prompt {:?}", t1.elapsed());
+    Ok((scratchpad, prompt))
+}
+
+pub async fn handle_v1_code_completion(
+    global_context: SharedGlobalContext,
+    code_completion_post: &mut CodeCompletionPost,
+) -> Result<Response<Body>, ScratchError> {
+    let (caps, model_name, scratchpad_name, scratchpad_patch, n_ctx) =
+        code_completion_post_validate_and_patch(global_context.clone(), code_completion_post).await?;
+    let (client1, api_key, cache_arc) = {
         let cx_locked = global_context.write().await;
-        (cx_locked.http_client.clone(), cx_locked.cmdline.api_key.clone(), cx_locked.completions_cache.clone(), cx_locked.telemetry.clone())
+        (cx_locked.http_client.clone(), cx_locked.cmdline.api_key.clone(), cx_locked.completions_cache.clone())
     };
     if !code_completion_post.no_cache {
         let cache_key = completion_cache::cache_key_from_post(&code_completion_post);
@@ -73,27 +131,15 @@ pub async fn handle_v1_code_completion(
         }
     }
 
-    let mut scratchpad = scratchpads::create_code_completion_scratchpad(
+    let (scratchpad, prompt) = code_completion_scratchpad_and_prompt(
         global_context.clone(),
         caps,
-        model_name.clone(),
-        code_completion_post.clone(),
+        &model_name,
+        code_completion_post,
         &scratchpad_name,
         &scratchpad_patch,
-        cache_arc.clone(),
-        tele_storage.clone(),
-    ).await.map_err(|e|
-        ScratchError::new(StatusCode::BAD_REQUEST, e)
-    )?;
-    let t1 = std::time::Instant::now();
-    let prompt = scratchpad.prompt(
         n_ctx,
-        &mut code_completion_post.parameters,
-    ).await.map_err(|e|
-        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Prompt: {}", e))
-    )?;
-    // info!("prompt {:?}\n{}", t1.elapsed(), prompt);
-    info!("prompt {:?}", t1.elapsed());
+    ).await?;
     if !code_completion_post.stream {
         crate::restream::scratchpad_interaction_not_stream(global_context.clone(), scratchpad, "completion".to_string(), &prompt, model_name, client1, api_key, &code_completion_post.parameters).await
     } else {
@@ -108,5 +154,23 @@ pub async fn handle_v1_code_completion_web(
     let mut code_completion_post = serde_json::from_slice::<CodeCompletionPost>(&body_bytes).map_err(|e|
         ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
     )?;
+    code_completion_post_cursor_to_chars(&mut code_completion_post)?;
     handle_v1_code_completion(global_context.clone(), &mut code_completion_post).await
 }
+
+pub fn code_completion_post_cursor_to_chars(
+    code_completion_post: &mut CodeCompletionPost,
+) -> Result<(), ScratchError> {
+    let cursor = &code_completion_post.inputs.cursor;
+    let source = code_completion_post.inputs.sources.get(&cursor.file).ok_or_else(||
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("cursor file \"{}\" not found in sources", cursor.file))
+    )?;
+    code_completion_post.inputs.cursor.character = position_encoding::cursor_to_chars(
+        source,
+        cursor.line,
+        cursor.character,
+        &code_completion_post.position_encoding,
+    ).map_err(|e| ScratchError::new(StatusCode::BAD_REQUEST, e))?;
+    code_completion_post.position_encoding = position_encoding::UTF32.to_string();
+    Ok(())
+}
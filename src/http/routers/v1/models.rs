@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::cached_tokenizers::tokenizer_cache_path;
+use crate::caps::{ModelRecord, which_scratchpad_to_use};
+use crate::custom_error::ScratchError;
+use crate::global_context::SharedGlobalContext;
+
+
+#[derive(Debug, Serialize)]
+struct ModelInfo {
+    name: String,
+    n_ctx: usize,                   // what prompts are actually built with, for completion models less than the model can do
+    scratchpads: Vec<String>,
+    default_scratchpad: String,     // what an empty "scratchpad" in a request turns into, empty if ambiguous
+    default: bool,
+    tokenizer_cached: bool,         // loaded or downloaded, the first request won't wait for a download
+}
+
+#[derive(Debug, Serialize)]
+struct ModelsResponse {
+    // Only running models, the same filtered lists the requests are checked against
+    code_completion_models: Vec<ModelInfo>,
+    code_completion_default_model: String,
+    code_chat_models: Vec<ModelInfo>,
+    code_chat_default_model: String,
+    unknown_running_models: Vec<String>,    // running on the server, but no n_ctx or scratchpads known for them
+}
+
+fn _models_info(
+    models: &HashMap<String, ModelRecord>,
+    n_ctx_override: Option<usize>,
+    default_model: &str,
+    tokenizers_loaded: &[String],
+    cache_dir: &Path,
+) -> Vec<ModelInfo> {
+    let mut result: Vec<ModelInfo> = models.iter().map(|(name, rec)| {
+        let mut scratchpads: Vec<String> = rec.supports_scratchpads.keys().cloned().collect();
+        scratchpads.sort();
+        ModelInfo {
+            name: name.clone(),
+            n_ctx: n_ctx_override.unwrap_or(rec.n_ctx),
+            scratchpads,
+            default_scratchpad: which_scratchpad_to_use(&rec.supports_scratchpads, "", &rec.default_scratchpad)
+                .map(|(scratchpad_name, _)| scratchpad_name).unwrap_or_default(),
+            default: name == default_model,
+            tokenizer_cached: tokenizers_loaded.contains(name) || tokenizer_cache_path(cache_dir, name).exists(),
+        }
+    }).collect();
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    result
+}
+
+pub async fn handle_v1_models(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    _: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let caps = crate::global_context::try_load_caps_quickly_if_not_present(global_context.clone()).await.map_err(|e|
+        ScratchError::new(StatusCode::SERVICE_UNAVAILABLE, format!("{}", e))
+    )?;
+    let (settings, tokenizers_loaded, cache_dir) = {
+        let cx_locked = global_context.read().await;
+        (cx_locked.settings.clone(), cx_locked.tokenizer_map.keys().cloned().collect::<Vec<String>>(), cx_locked.cache_dir.clone())
+    };
+    let response = {
+        let caps_locked = caps.read().unwrap();
+        // Plugin settings replace caps defaults, same as in code_completion and chat handlers
+        let code_completion_default_model = if settings.code_completion_model.is_empty() { caps_locked.code_completion_default_model.clone() } else { settings.code_completion_model.clone() };
+        let code_chat_default_model = if settings.chat_model.is_empty() { caps_locked.code_chat_default_model.clone() } else { settings.chat_model.clone() };
+        let unknown_running_models = caps_locked.running_models.iter()
+            .filter(|m| !caps_locked.code_completion_models.contains_key(*m) && !caps_locked.code_chat_models.contains_key(*m))
+            .cloned()
+            .collect();
+        ModelsResponse {
+            code_completion_models: _models_info(&caps_locked.code_completion_models, Some(crate::caps::code_completion_n_ctx(&caps_locked)), &code_completion_default_model, &tokenizers_loaded, &cache_dir),
+            code_completion_default_model,
+            code_chat_models: _models_info(&caps_locked.code_chat_models, None, &code_chat_default_model, &tokenizers_loaded, &cache_dir),
+            code_chat_default_model,
+            unknown_running_models,
+        }
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&response).unwrap()))
+        .unwrap())
+}
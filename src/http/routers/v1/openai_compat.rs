@@ -0,0 +1,231 @@
+use axum::Extension;
+use axum::response::Result;
+use async_stream::stream;
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::call_validation::{ChatMessage, ChatPost, CodeCompletionInputs, CodeCompletionPost, CursorPosition, SamplingParameters};
+use crate::custom_error::ScratchError;
+use crate::global_context::SharedGlobalContext;
+use crate::http::routers::v1::chat::handle_v1_chat;
+use crate::http::routers::v1::code_completion::handle_v1_code_completion;
+use crate::position_encoding;
+use crate::restream::{ChatAnswer, RestreamReader};
+
+// Tools that speak OpenAI API get caps, scratchpads and the completion cache: requests are mapped
+// to CodeCompletionPost / ChatPost, answers and SSE chunks are converted back to OpenAI shapes.
+
+const OPENAI_COMPLETION_FILE: &str = "openai-completion";
+
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum StringOrList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StringOrList {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            StringOrList::One(s) => vec![s],
+            StringOrList::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAICompletionPost {
+    #[serde(default)]
+    model: String,
+    prompt: StringOrList,
+    #[serde(default)]
+    suffix: Option<String>,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    stop: Option<StringOrList>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatPost {
+    #[serde(default)]
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    stop: Option<StringOrList>,
+    #[serde(default)]
+    stream: bool,
+}
+
+
+pub async fn handle_v1_openai_completions(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = serde_json::from_slice::<OpenAICompletionPost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    let mut prompts = post.prompt.into_vec();
+    if prompts.len() != 1 {
+        return Err(ScratchError::new(StatusCode::BAD_REQUEST, format!("exactly one prompt is supported, got {}", prompts.len())));
+    }
+    // The prompt is the text before cursor, the suffix is the text after: that's a FIM request on a virtual file
+    let prompt = prompts.remove(0);
+    let cursor_line = prompt.matches('\n').count() as i32;
+    let cursor_character = prompt.rsplit('\n').next().unwrap_or("").chars().count() as i32;
+    let mut code_completion_post = CodeCompletionPost {
+        inputs: CodeCompletionInputs {
+            sources: [(OPENAI_COMPLETION_FILE.to_string(), format!("{}{}", prompt, post.suffix.unwrap_or_default()))].into(),
+            cursor: CursorPosition {
+                file: OPENAI_COMPLETION_FILE.to_string(),
+                line: cursor_line,
+                character: cursor_character,
+            },
+            multiline: true,
+        },
+        parameters: SamplingParameters {
+            max_new_tokens: post.max_tokens.unwrap_or(0),
+            temperature: post.temperature,
+            top_p: post.top_p,
+            stop: post.stop.map(|s| s.into_vec()),
+        },
+        model: post.model,
+        scratchpad: "".to_string(),
+        stream: post.stream,
+        no_cache: false,
+        position_encoding: position_encoding::UTF32.to_string(),
+    };
+    let resp = handle_v1_code_completion(global_context.clone(), &mut code_completion_post).await?;
+    let id = format!("cmpl-{}", chrono::Local::now().timestamp_millis());
+    let model = code_completion_post.model.clone();
+    let convert = move |value: &Value, object: &str| -> Value {
+        let choices: Vec<Value> = value.get("choices").and_then(|c| c.as_array()).cloned().unwrap_or_default()
+            .iter()
+            .map(|c| json!({
+                "index": c.get("index").cloned().unwrap_or(json!(0)),
+                "text": c.get("code_completion").cloned().unwrap_or(json!("")),
+                "finish_reason": c.get("finish_reason").cloned().unwrap_or(Value::Null),
+                "logprobs": Value::Null,
+            }))
+            .collect();
+        json!({
+            "id": id,
+            "object": object,
+            "created": chrono::Local::now().timestamp(),
+            "model": value.get("model").cloned().unwrap_or(json!(model)),
+            "choices": choices,
+        })
+    };
+    if !code_completion_post.stream {
+        let value = _read_json_body(resp).await?;
+        let response = convert(&value, "text_completion");
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&response).unwrap()))
+            .unwrap());
+    }
+    Ok(_openai_stream(resp.into_body(), move |value| convert(value, "text_completion")))
+}
+
+pub async fn handle_v1_openai_chat_completions(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = serde_json::from_slice::<OpenAIChatPost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    let mut chat_post = ChatPost {
+        messages: post.messages,
+        parameters: SamplingParameters {
+            max_new_tokens: post.max_tokens.unwrap_or(0),
+            temperature: post.temperature,
+            top_p: post.top_p,
+            stop: post.stop.map(|s| s.into_vec()),
+        },
+        model: post.model,
+        scratchpad: "".to_string(),
+        stream: Some(post.stream),
+    };
+    let resp = handle_v1_chat(global_context.clone(), &mut chat_post).await?;
+    let id = format!("chatcmpl-{}", chrono::Local::now().timestamp_millis());
+    let model = chat_post.model.clone();
+    if post.stream {
+        return Ok(_openai_stream(resp.into_body(), move |value| json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": chrono::Local::now().timestamp(),
+            "model": value.get("model").cloned().unwrap_or(json!(model)),
+            "choices": value.get("choices").cloned().unwrap_or(json!([])),
+        })));
+    }
+    // Chat scratchpads always stream, collect the deltas into one message
+    let mut reader = RestreamReader::new(resp.into_body());
+    let mut answer = ChatAnswer::new(&model);
+    while let Some(value) = reader.next().await {
+        let value = value.map_err(|e| ScratchError::new_but_skip_telemetry(StatusCode::INTERNAL_SERVER_ERROR, format!("chat: {}", e)))?;
+        answer.add_chunk(&value);
+    }
+    let finish_reason = if answer.finish_reason.is_empty() { Value::Null } else { json!(answer.finish_reason) };
+    let response = json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": chrono::Local::now().timestamp(),
+        "model": answer.model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": answer.role, "content": answer.content},
+            "finish_reason": finish_reason,
+        }],
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&response).unwrap()))
+        .unwrap())
+}
+
+fn _openai_stream(
+    body: Body,
+    convert: impl Fn(&Value) -> Value + Send + 'static,
+) -> Response<Body> {
+    let evstream = stream! {
+        let mut reader = RestreamReader::new(body);
+        while let Some(value) = reader.next().await {
+            let chunk = match value {
+                Ok(value) => convert(&value),
+                Err(e) => json!({"error": {"message": e}}),
+            };
+            yield Result::<_, String>::Ok(format!("data: {}\n\n", chunk));
+        }
+        // OpenAI clients wait for this, even if the stream ended with an error
+        yield Result::<_, String>::Ok("data: [DONE]\n\n".to_string());
+    };
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .body(Body::wrap_stream(evstream))
+        .unwrap()
+}
+
+async fn _read_json_body(resp: Response<Body>) -> Result<Value, ScratchError> {
+    let body_bytes = hyper::body::to_bytes(resp.into_body()).await.map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e))
+    )?;
+    serde_json::from_slice::<Value>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("JSON problem: {}", e))
+    )
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::cached_tokenizers;
+use crate::call_validation::{ChatPost, CodeCompletionPost, SamplingParameters};
+use crate::caps::CodeAssistantCaps;
+use crate::custom_error::ScratchError;
+use crate::global_context::SharedGlobalContext;
+use crate::http::routers::v1::chat::{chat_post_validate_and_patch, chat_scratchpad_and_prompt};
+use crate::http::routers::v1::code_completion::{code_completion_post_cursor_to_chars, code_completion_post_validate_and_patch, code_completion_scratchpad_and_prompt};
+
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptPreview {
+    // Everything that would be sent to the model, but the model is not called
+    pub prompt: String,
+    pub tokens: Option<usize>,       // null for scratchpads that don't tokenize, such as PASSTHROUGH
+    pub parameters: SamplingParameters,
+    pub model: String,
+    pub scratchpad: String,
+}
+
+async fn _count_tokens(
+    global_context: SharedGlobalContext,
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    model_name: &str,
+    scratchpad_name: &str,
+    prompt: &str,
+) -> Result<Option<usize>, ScratchError> {
+    if scratchpad_name == "PASSTHROUGH" {
+        return Ok(None);
+    }
+    let tokenizer = cached_tokenizers::cached_tokenizer(caps, global_context, model_name.to_string()).await.map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("tokenizer: {}", e))
+    )?;
+    let tokenizer_locked = tokenizer.read().unwrap();
+    let encoding = tokenizer_locked.encode(prompt, false).map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Encoding error: {}", e))
+    )?;
+    Ok(Some(encoding.len()))
+}
+
+pub async fn code_completion_prompt_preview(
+    global_context: SharedGlobalContext,
+    code_completion_post: &mut CodeCompletionPost,
+) -> Result<PromptPreview, ScratchError> {
+    let (caps, model_name, scratchpad_name, scratchpad_patch, n_ctx) =
+        code_completion_post_validate_and_patch(global_context.clone(), code_completion_post).await?;
+    let (_, prompt) = code_completion_scratchpad_and_prompt(
+        global_context.clone(),
+        caps.clone(),
+        &model_name,
+        code_completion_post,
+        &scratchpad_name,
+        &scratchpad_patch,
+        n_ctx,
+    ).await?;
+    let tokens = _count_tokens(global_context.clone(), caps, &model_name, &scratchpad_name, &prompt).await?;
+    Ok(PromptPreview {
+        prompt,
+        tokens,
+        parameters: code_completion_post.parameters.clone(),
+        model: model_name,
+        scratchpad: scratchpad_name,
+    })
+}
+
+pub async fn chat_prompt_preview(
+    global_context: SharedGlobalContext,
+    chat_post: &mut ChatPost,
+) -> Result<PromptPreview, ScratchError> {
+    let (caps, model_name, scratchpad_name, scratchpad_patch) =
+        chat_post_validate_and_patch(global_context.clone(), chat_post).await?;
+    let (_, prompt) = chat_scratchpad_and_prompt(
+        global_context.clone(),
+        caps.clone(),
+        &model_name,
+        chat_post,
+        &scratchpad_name,
+        &scratchpad_patch,
+    ).await?;
+    let tokens = _count_tokens(global_context.clone(), caps, &model_name, &scratchpad_name, &prompt).await?;
+    Ok(PromptPreview {
+        prompt,
+        tokens,
+        parameters: chat_post.parameters.clone(),
+        model: model_name,
+        scratchpad: scratchpad_name,
+    })
+}
+
+pub async fn handle_v1_prompt_preview(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    // Takes the same body as /v1/code-completion or /v1/chat, tells them apart by "messages"
+    let value = serde_json::from_slice::<serde_json::Value>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    let preview = if value.get("messages").is_some() {
+        let mut chat_post = serde_json::from_value::<ChatPost>(value).map_err(|e|
+            ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+        )?;
+        chat_prompt_preview(global_context.clone(), &mut chat_post).await?
+    } else {
+        let mut code_completion_post = serde_json::from_value::<CodeCompletionPost>(value).map_err(|e|
+            ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+        )?;
+        code_completion_post_cursor_to_chars(&mut code_completion_post)?;
+        code_completion_prompt_preview(global_context.clone(), &mut code_completion_post).await?
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&preview).unwrap()))
+        .unwrap())
+}
@@ -0,0 +1,105 @@
+use std::time::Instant;
+
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use serde_json::json;
+use tokio::sync::Mutex as AMutex;
+
+use crate::custom_error::ScratchError;
+use crate::global_context::SharedGlobalContext;
+use crate::telemetry::utils::{sorted_json_files, telemetry_storage_dirs};
+
+const ENDPOINT_PROBE_TIMEOUT: u64 = 3;  // seconds
+const ENDPOINT_PROBE_TTL: u64 = 30;     // seconds, status bars poll this, the model server shouldn't feel it
+
+
+async fn _probe_endpoint(client: &reqwest::Client, url: &str) -> serde_json::Value {
+    // Any HTTP answer means the server is there, even 404 or 405 for a GET on a completion endpoint
+    let resp = client.get(url)
+        .timeout(std::time::Duration::from_secs(ENDPOINT_PROBE_TIMEOUT))
+        .send()
+        .await;
+    match resp {
+        Ok(resp) => json!({"url": url, "reachable": true, "status_code": resp.status().as_u16()}),
+        Err(e) => json!({"url": url, "reachable": false, "error": format!("{}", e)}),
+    }
+}
+
+async fn _probe_endpoint_cached(
+    probe_cache: &AMutex<Option<(Instant, serde_json::Value)>>,
+    client: &reqwest::Client,
+    url: &str,
+) -> serde_json::Value {
+    // Holding the lock while probing, concurrent requests wait for this probe instead of making their own
+    let mut probe_cache_locked = probe_cache.lock().await;
+    let fresh = probe_cache_locked.as_ref()
+        .filter(|(checked, probe)| checked.elapsed().as_secs() < ENDPOINT_PROBE_TTL && probe["url"] == url)
+        .cloned();
+    let (checked, mut probe) = match fresh {
+        Some(cached) => cached,
+        None => {
+            let probed = (Instant::now(), _probe_endpoint(client, url).await);
+            *probe_cache_locked = Some(probed.clone());
+            probed
+        }
+    };
+    probe["checked_seconds_ago"] = json!(checked.elapsed().as_secs());
+    probe
+}
+
+pub async fn handle_v1_status(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    _: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let (http_client, endpoint_probe, caps, caps_last_attempted_ts, status, mut tokenizers, lsp_clients_count, uptime, cache_dir) = {
+        let cx_locked = global_context.read().await;
+        (
+            cx_locked.http_client.clone(),
+            cx_locked.endpoint_probe.clone(),
+            cx_locked.caps.clone(),
+            cx_locked.caps_last_attempted_ts,
+            cx_locked.status.clone(),
+            cx_locked.tokenizer_map.keys().cloned().collect::<Vec<String>>(),
+            cx_locked.lsp_clients_count,
+            cx_locked.started.elapsed().as_secs(),
+            cx_locked.cache_dir.clone(),
+        )
+    };
+    tokenizers.sort();
+    let endpoint = match &caps {
+        Some(caps) => {
+            let url = {
+                let caps_locked = caps.read().unwrap();
+                caps_locked.endpoint_template.replace("$MODEL", &caps_locked.code_completion_default_model)
+            };
+            _probe_endpoint_cached(&endpoint_probe, &http_client, &url).await
+        },
+        None => serde_json::Value::Null,
+    };
+    let (dir_compressed, dir_sent) = telemetry_storage_dirs(&cache_dir).await;
+    let body = json!({
+        "caps": {
+            "loaded": caps.is_some(),
+            "state": status.caps,
+            "version": status.caps_version,
+            "last_attempted_ts": caps_last_attempted_ts,
+            "last_error": status.caps_error,
+        },
+        "tokenizers": tokenizers,
+        "endpoint": endpoint,
+        "model_reachable": status.model_reachable,
+        "last_error": status.last_error,
+        "lsp_clients_count": lsp_clients_count,
+        "uptime_seconds": uptime,
+        "telemetry_files": {
+            "compressed": sorted_json_files(dir_compressed).await.len(),
+            "sent": sorted_json_files(dir_sent).await.len(),
+        },
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&body).unwrap()))
+        .unwrap())
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokenizers::Tokenizer;
+
+use crate::cached_tokenizers;
+use crate::caps;
+use crate::caps::CodeAssistantCaps;
+use crate::custom_error::ScratchError;
+use crate::global_context::SharedGlobalContext;
+
+
+#[derive(Debug, Deserialize)]
+struct TokenizePost {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    chat: bool,     // empty model means the default chat model, not the default completion model
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DetokenizePost {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    chat: bool,
+    tokens: Vec<u32>,
+}
+
+fn _lookup_tokenizer_model(
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    model: &str,
+    chat: bool,
+) -> Result<(String, usize), String> {
+    // A model name can be in either list, the flag only decides which default to take.
+    // Completions are built with a shorter context than the model can do, report that one.
+    let caps_locked = caps.read().unwrap();
+    let completion_n_ctx = caps::code_completion_n_ctx(&caps_locked);
+    let completion = caps::which_model_to_use(&caps_locked.code_completion_models, model, &caps_locked.code_completion_default_model)
+        .map(|(name, _)| (name, completion_n_ctx));
+    let chat_model = caps::which_model_to_use(&caps_locked.code_chat_models, model, &caps_locked.code_chat_default_model)
+        .map(|(name, rec)| (name, rec.n_ctx));
+    let (first, second) = if chat { (chat_model, completion) } else { (completion, chat_model) };
+    match first {
+        Ok(x) => Ok(x),
+        Err(e1) if !model.is_empty() => second.map_err(|e2| format!("{}\n{}", e1, e2)),
+        Err(e1) => Err(e1),
+    }
+}
+
+async fn _model_tokenizer(
+    global_context: SharedGlobalContext,
+    model: &str,
+    chat: bool,
+) -> Result<(String, usize, Arc<StdRwLock<Tokenizer>>), ScratchError> {
+    let caps = crate::global_context::try_load_caps_quickly_if_not_present(global_context.clone()).await?;
+    let (model_name, n_ctx) = _lookup_tokenizer_model(caps.clone(), model, chat).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, e)
+    )?;
+    let tokenizer = cached_tokenizers::cached_tokenizer(caps, global_context, model_name.clone()).await.map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("tokenizer for {}: {}", model_name, e))
+    )?;
+    Ok((model_name, n_ctx, tokenizer))
+}
+
+pub async fn handle_v1_tokenize(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = serde_json::from_slice::<TokenizePost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    let (model_name, n_ctx, tokenizer) = _model_tokenizer(global_context.clone(), &post.model, post.chat).await?;
+    // Offsets count unicode chars, same as cursor positions elsewhere
+    let encoding = tokenizer.read().unwrap().encode_char_offsets(post.text.as_str(), false).map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Encoding error: {}", e))
+    )?;
+    let response = json!({
+        "model": model_name,
+        "n_ctx": n_ctx,
+        "count": encoding.len(),
+        "tokens": encoding.get_ids(),
+        "offsets": encoding.get_offsets(),
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&response).unwrap()))
+        .unwrap())
+}
+
+pub async fn handle_v1_detokenize(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = serde_json::from_slice::<DetokenizePost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    let (model_name, n_ctx, tokenizer) = _model_tokenizer(global_context.clone(), &post.model, post.chat).await?;
+    let text = tokenizer.read().unwrap().decode(&post.tokens, false).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("Decoding error: {}", e))
+    )?;
+    let response = json!({
+        "model": model_name,
+        "n_ctx": n_ctx,
+        "text": text,
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&response).unwrap()))
+        .unwrap())
+}
+
+pub async fn handle_v1_count_tokens(
+    Extension(global_context): Extension<SharedGlobalContext>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = serde_json::from_slice::<TokenizePost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    let (model_name, n_ctx, tokenizer) = _model_tokenizer(global_context.clone(), &post.model, post.chat).await?;
+    let encoding = tokenizer.read().unwrap().encode(post.text.as_str(), false).map_err(|e|
+        ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Encoding error: {}", e))
+    )?;
+    let response = json!({
+        "model": model_name,
+        "n_ctx": n_ctx,
+        "count": encoding.len(),
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&response).unwrap()))
+        .unwrap())
+}
@@ -2,7 +2,9 @@ use std::future::Future;
 use std::pin::Pin;
 use tracing::{info, error};
 use axum::Extension;
-use axum::http::{Method, Uri};
+use axum::http::{Method, Request, Uri};
+use axum::middleware::Next;
+use axum::response::IntoResponse;
 use hyper::{Body, Response};
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
@@ -16,6 +18,13 @@ pub async fn telemetry_wrapper(func: impl Fn(Extension<SharedGlobalContext>, hyp
                                body_bytes: hyper::body::Bytes) -> Result<Response<Body>, ScratchError> {
     let t0 = std::time::Instant::now();
     let result = Box::pin(func(ex.clone(), body_bytes)).await;
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(e) => e.status_code,
+    };
+    // For streaming responses that's the time to headers, the stream itself is in upstream latency
+    let metrics = ex.read().await.metrics.clone();
+    crate::metrics::http_request(&metrics, path.path(), status.as_u16(), t0.elapsed());
     if let Err(e) = result {
         if !e.telemetry_skip {
             let tele_storage = &ex.read().await.telemetry;
@@ -34,6 +43,29 @@ pub async fn telemetry_wrapper(func: impl Fn(Extension<SharedGlobalContext>, hyp
     return Ok(result.unwrap());
 }
 
+pub async fn rate_limit_layer<B>(request: Request<B>, next: Next<B>) -> axum::response::Response {
+    // Extension with the global context is added in start_server, outside of this layer
+    if let Some(gcx) = request.extensions().get::<SharedGlobalContext>().cloned() {
+        let (limits, tele_storage, metrics) = {
+            let cx_locked = gcx.read().await;
+            (cx_locked.limits.clone(), cx_locked.telemetry.clone(), cx_locked.metrics.clone())
+        };
+        let path = request.uri().path().to_string();
+        if !crate::limits::route_allowed(&limits, &path) {
+            let e = crate::limits::too_many_requests(
+                &tele_storage,
+                &path,
+                &format!("{}", request.method()),
+                format!("rate limit for {} exceeded, try again later", path),
+            );
+            crate::metrics::http_request(&metrics, &path, e.status_code.as_u16(), std::time::Duration::ZERO);
+            error!("{} returning \"{}\"", path, e.status_code);
+            return e.to_response().into_response();
+        }
+    }
+    next.run(request).await
+}
+
 #[macro_export]
 macro_rules! telemetry_post {
     (
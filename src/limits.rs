@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+use std::time::Instant;
+
+use hyper::StatusCode;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::custom_error::ScratchError;
+use crate::global_context::CommandLine;
+use crate::telemetry::telemetry_structs;
+
+// Protects the upstream server from a runaway client. Nothing waits in a queue: a request over the limit
+// gets 429 right away, the client is supposed to retry later. Route limits are a layer on make_v1_router,
+// model limits are taken in restream, because the model is only known after the scratchpad is chosen.
+
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct Limits {
+    pub max_concurrent_per_model: usize,          // 0 means no limit
+    pub route_rates: HashMap<String, f64>,        // requests per second, bursts up to max(rate, 1)
+    model_semaphores: HashMap<String, Arc<Semaphore>>,
+    route_buckets: HashMap<String, TokenBucket>,
+}
+
+impl Limits {
+    pub fn new(cmdline: &CommandLine) -> Self {
+        Limits {
+            max_concurrent_per_model: cmdline.max_concurrent_per_model,
+            route_rates: cmdline.http_rate_limits.clone(),
+            model_semaphores: HashMap::new(),
+            route_buckets: HashMap::new(),
+        }
+    }
+}
+
+fn _normalize_route(route: &str) -> String {
+    // Routes are matched inside make_v1_router, where the path doesn't have /v1
+    let route = route.trim().trim_start_matches("/v1/").trim_start_matches('/');
+    format!("/{}", route)
+}
+
+pub fn parse_route_rates(arg: &str) -> Result<HashMap<String, f64>, String> {
+    // "/v1/code-completion=20,chat=0.5"
+    let mut route_rates = HashMap::new();
+    for item in arg.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let (route, rate) = item.split_once('=').ok_or(format!("expected route=rate, got \"{}\"", item))?;
+        let rate = rate.trim().parse::<f64>().map_err(|e| format!("rate for \"{}\": {}", route, e))?;
+        if rate <= 0.0 {
+            return Err(format!("rate for \"{}\" must be positive", route));
+        }
+        route_rates.insert(_normalize_route(route), rate);
+    }
+    Ok(route_rates)
+}
+
+pub fn route_allowed(limits: &Arc<StdRwLock<Limits>>, route: &str) -> bool {
+    let mut limits_locked = limits.write().unwrap();
+    let rate = match limits_locked.route_rates.get(route) {
+        Some(rate) => *rate,
+        None => return true,
+    };
+    let capacity = rate.max(1.0);
+    let now = Instant::now();
+    let bucket = limits_locked.route_buckets.entry(route.to_string()).or_insert(TokenBucket { tokens: capacity, updated: now });
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+    bucket.updated = now;
+    if bucket.tokens < 1.0 {
+        return false;
+    }
+    bucket.tokens -= 1.0;
+    true
+}
+
+pub fn model_permit(limits: &Arc<StdRwLock<Limits>>, model: &str) -> Result<Option<OwnedSemaphorePermit>, usize> {
+    let semaphore = {
+        let mut limits_locked = limits.write().unwrap();
+        let max_concurrent = limits_locked.max_concurrent_per_model;
+        if max_concurrent == 0 {
+            return Ok(None);
+        }
+        limits_locked.model_semaphores.entry(model.to_string()).or_insert_with(|| Arc::new(Semaphore::new(max_concurrent))).clone()
+    };
+    // The permit is released when dropped, that is when the upstream call (or stream) is over
+    semaphore.try_acquire_owned().map(Some).map_err(|_| limits.read().unwrap().max_concurrent_per_model)
+}
+
+pub fn too_many_requests(
+    tele_storage: &Arc<StdRwLock<telemetry_structs::Storage>>,
+    url: &str,
+    scope: &str,
+    message: String,
+) -> ScratchError {
+    // Recorded here, because LSP calls the handlers directly, without telemetry_wrapper
+    tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
+        url.to_string(),
+        scope.to_string(),
+        false,
+        message.clone(),
+    ));
+    ScratchError::new_but_skip_telemetry(StatusCode::TOO_MANY_REQUESTS, message)
+}
@@ -1,25 +1,39 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::sync::Arc;
 use std::time::Instant;
 
+use futures::future::{AbortHandle, Abortable, Aborted};
 use ropey::Rope;
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
 use tokio::sync::RwLock as ARwLock;
 use tokio::task::JoinHandle;
+use tower::ServiceExt;
 use tower_lsp::{ClientSocket, LanguageServer, LspService};
 use tower_lsp::jsonrpc::{Error, Result};
 use tower_lsp::lsp_types::*;
 use tracing::{error, info};
+use similar::{DiffTag, TextDiff};
 
-use crate::call_validation::{CodeCompletionInputs, CodeCompletionPost, CursorPosition, SamplingParameters};
+use crate::call_validation::{ChatMessage, ChatPost, CodeCompletionInputs, CodeCompletionPost, CursorPosition, SamplingParameters};
 use crate::{global_context, lsp};
-use crate::global_context::{CommandLine, SharedGlobalContext};
+use crate::custom_error::ScratchError;
+use crate::global_context::{CommandLine, RuntimeSettings, SharedGlobalContext};
+use crate::http::routers::v1::chat::handle_v1_chat;
 use crate::http::routers::v1::code_completion::handle_v1_code_completion;
+use crate::http::routers::v1::prompt_preview::{code_completion_prompt_preview, PromptPreview};
 use crate::telemetry;
+use crate::position_encoding;
+use crate::restream::{ChatAnswer, RestreamReader};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const TRIGGER_CHARACTERS: [&str; 2] = [".", "("];
+const COMMAND_EXPLAIN: &str = "refact.explain";
+const COMMAND_ADD_DOCSTRING: &str = "refact.addDocstring";
+const COMMAND_REFACTOR: &str = "refact.refactor";
+const DEFAULT_REFACTOR_INSTRUCTION: &str = "Refactor this code to make it cleaner and easier to read, keep the behavior the same.";
 
 
 #[derive(Debug, Deserialize)]
@@ -36,30 +50,47 @@ impl Display for APIError {
 
 #[derive(Debug)]
 pub struct Document {
-    #[allow(dead_code)]
     pub language_id: String,
     pub text: Rope,
+    pub last_changed: Instant,
 }
 
 impl Document {
     fn new(language_id: String, text: Rope) -> Self {
-        Self { language_id, text }
+        Self { language_id, text, last_changed: Instant::now() }
     }
 }
 
+// (uri, request kind) -> the running completion
+type PendingCompletions = HashMap<(String, &'static str), Arc<AbortHandle>>;
+
 // #[derive(Debug)]  GlobalContext does not implement Debug
 pub struct Backend {
     pub gcx: Arc<ARwLock<global_context::GlobalContext>>,
     pub client: tower_lsp::Client,
     pub document_map: Arc<ARwLock<HashMap<String, Document>>>,
     pub workspace_folders: Arc<ARwLock<Option<Vec<WorkspaceFolder>>>>,
+    pub position_encoding: Arc<ARwLock<PositionEncodingKind>>,
+    pub pending_completions: Arc<ARwLock<PendingCompletions>>,
+    pub work_done_progress: Arc<ARwLock<bool>>,
+    pub status_forwarder: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
 }
 
+impl Drop for Backend {
+    fn drop(&mut self) {
+        if let Some(handle) = self.status_forwarder.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct RequestParams {
-    pub max_new_tokens: u32,
-    pub temperature: f32,
+    #[serde(default)]
+    pub max_new_tokens: u32,    // zero means the server default
+    #[serde(default)]
+    pub temperature: Option<f32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -73,9 +104,52 @@ pub struct CompletionParams1 {
     pub text_document_position: TextDocumentPositionParams,
     pub parameters: RequestParams,
     pub multiline: bool,
+    #[serde(default)]
+    pub text: Option<String>,    // whole file, for clients that want a completion without didOpen
     // pub model: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatParams {
+    #[serde(default)]
+    pub chat_id: String,     // echoed back in refact/chatDelta, so the plugin knows which chat the delta belongs to
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub parameters: SamplingParameters,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub scratchpad: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatRes {
+    pub chat_id: String,
+    pub message: ChatMessage,
+    pub finish_reason: String,
+    pub model: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatDeltaParams {
+    pub chat_id: String,
+    pub data: serde_json::Value,   // the same json as in "data: ..." lines of /v1/chat
+}
+
+pub enum ChatDelta {}
+
+impl notification::Notification for ChatDelta {
+    type Params = ChatDeltaParams;
+    const METHOD: &'static str = "refact/chatDelta";
+}
+
+pub enum RefactStatusNotification {}
+
+impl notification::Notification for RefactStatusNotification {
+    type Params = global_context::RefactStatus;
+    const METHOD: &'static str = "refact/status";
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TestHeadTailAddedText {
     pub text_a: String,
@@ -84,6 +158,11 @@ pub struct TestHeadTailAddedText {
 }
 
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SnippetTelemetryParams {
+    pub snippet_telemetry_id: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TestHeadTailAddedTextRes {
     pub is_valid: bool,
@@ -102,6 +181,21 @@ fn internal_error<E: Display>(err: E) -> Error {
     }
 }
 
+fn scratch_error_to_jsonrpc(err: ScratchError) -> Error {
+    // Bad requests are the client's fault, the rest is on our side or upstream
+    error!("{}", err);
+    let code = if err.status_code.is_client_error() {
+        tower_lsp::jsonrpc::ErrorCode::InvalidParams
+    } else {
+        tower_lsp::jsonrpc::ErrorCode::InternalError
+    };
+    Error {
+        code,
+        message: err.message.clone().into(),
+        data: Some(serde_json::json!({"status": err.status_code.as_u16(), "detail": err.message})),
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct Choice {
     pub index: u32,
@@ -136,7 +230,8 @@ pub struct InlineCompletionParams{
 #[serde(rename_all = "camelCase")]
 pub struct InlineCompletionContext{
     pub trigger_kind: InlineCompletionTriggerKind,
-    pub selected_completion_info : SelectedCompletionInfo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selected_completion_info : Option<SelectedCompletionInfo>
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -146,10 +241,30 @@ pub struct SelectedCompletionInfo{
     pub text: String 
 }
 
-#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum InlineCompletionTriggerKind{
-    Invoke = 0,
-    Automatic = 1,
+    Invoked = 1,
+    Automatic = 2,
+}
+
+// The spec sends trigger kind as a number, but older plugins send variant names, accept both
+impl Serialize for InlineCompletionTriggerKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for InlineCompletionTriggerKind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        match &value {
+            serde_json::Value::Number(n) if n.as_u64() == Some(1) => Ok(InlineCompletionTriggerKind::Invoked),
+            serde_json::Value::Number(n) if n.as_u64() == Some(2) => Ok(InlineCompletionTriggerKind::Automatic),
+            serde_json::Value::String(s) if s == "Invoked" || s == "Invoke" => Ok(InlineCompletionTriggerKind::Invoked),
+            serde_json::Value::String(s) if s == "Automatic" => Ok(InlineCompletionTriggerKind::Automatic),
+            _ => Err(serde::de::Error::custom(format!("unknown inline completion trigger kind {}", value))),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize)]
@@ -165,6 +280,9 @@ pub struct InlineCompletionItem{
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub filter_text: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
     
     #[serde(skip_serializing_if = "Option::is_none")]
     pub command: Option<Command>,
@@ -180,79 +298,283 @@ pub enum InsertTextFormat{
 }
 
 impl Backend {
-    async fn flat_params_to_code_completion_post(&self, params: &CompletionParams1) -> CodeCompletionPost {
-
+    async fn flat_params_to_code_completion_post(&self, params: &CompletionParams1) -> Result<CodeCompletionPost> {
+        let encoding = self.position_encoding.read().await.clone();
+        let open_tabs_tokens = self.gcx.read().await.cmdline.lsp_open_tabs_tokens;
+        let uri = params.text_document_position.text_document.uri.as_str();
         let document_map = self.document_map.read().await;
-        let document = document_map
-            .get(params.text_document_position.text_document.uri.as_str())
-            .unwrap();
-        let txt = &document.text;
-        CodeCompletionPost {
+        let txt = match &params.text {
+            Some(text) => Rope::from_str(text),
+            None => match document_map.get(uri) {
+                Some(document) => document.text.clone(),
+                None => return Err(Error::invalid_params(format!(
+                    "document {} is not open (or was forgotten because of --lsp-documents-max-bytes), send didOpen first or pass \"text\"", uri
+                ))),
+            },
+        };
+        let mut sources = open_tabs_context(&document_map, uri, &txt, open_tabs_tokens);
+        drop(document_map);
+        sources.insert(uri.to_string(), txt.to_string());
+        let position = &params.text_document_position.position;
+        let character = if (position.line as usize) < txt.len_lines() {
+            position_encoding::units_to_char_in_line(txt.line(position.line as usize), position.character as usize, encoding.as_str())
+        } else {
+            position.character as usize
+        };
+        Ok(CodeCompletionPost {
             inputs: CodeCompletionInputs {
-                sources: HashMap::from([(String::from(&params.text_document_position.text_document.uri.to_string()),
-                                         (&txt).to_string())]),
+                sources,
                 cursor: CursorPosition {
                     file: String::from(&params.text_document_position.text_document.uri.to_string()),
                     line: params.text_document_position.position.line as i32,
-                    character: params.text_document_position.position.character as i32,
+                    character: character as i32,
                 },
                 multiline: params.multiline,
             },
             parameters: SamplingParameters {
                 max_new_tokens: params.parameters.max_new_tokens as usize,
-                temperature: Option::from(params.parameters.temperature),
+                temperature: params.parameters.temperature,
                 top_p: None,
                 stop: None,
             },
             model: "".to_string(),
             scratchpad: "".to_string(),
             stream: false,
-            no_cache: false
-        }
+            no_cache: false,
+            position_encoding: position_encoding::UTF32.to_string(),
+        })
     }
 
     pub async fn get_completions(&self, params: CompletionParams1) -> Result<CompletionRes> {
-        let mut post = self.flat_params_to_code_completion_post(&params).await;
+        self.get_completions_of_kind(params, "refact/getCompletions").await
+    }
 
-        let res = handle_v1_code_completion(self.gcx.clone(),
-                                            &mut post).await;
-        let resp = res.unwrap();
-        let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    async fn get_completions_of_kind(&self, params: CompletionParams1, kind: &'static str) -> Result<CompletionRes> {
+        let mut post = self.flat_params_to_code_completion_post(&params).await?;
+        self.code_completion_post_to_res(&mut post, kind).await
+    }
+
+    pub async fn prompt_preview(&self, params: CompletionParams1) -> Result<PromptPreview> {
+        // Same params as refact/getCompletions, shows what the model would see without calling it
+        let mut post = self.flat_params_to_code_completion_post(&params).await?;
+        code_completion_prompt_preview(self.gcx.clone(), &mut post).await.map_err(scratch_error_to_jsonrpc)
+    }
 
-        let s = String::from_utf8(body_bytes.to_vec()).unwrap();
-        let value = serde_json::from_str::<CompletionRes>(s.as_str()).map_err(|e| internal_error(e))?;
+    async fn code_completion_post_to_res(&self, post: &mut CodeCompletionPost, kind: &'static str) -> Result<CompletionRes> {
+        // User typed more, so the previous completion of the same kind for the same document is useless: abort it,
+        // that drops the upstream model call. $/cancelRequest drops this future in the same way (tower_lsp does that).
+        // Kinds are separate, because a client can ask for classic and inline completion on the same keystroke.
+        let uri = post.inputs.cursor.file.clone();
+        let key = (uri.clone(), kind);
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let abort_handle = Arc::new(abort_handle);
+        if let Some(older) = self.pending_completions.write().await.insert(key.clone(), abort_handle.clone()) {
+            older.abort();
+        }
+        let res_maybe = Abortable::new(
+            handle_v1_code_completion(self.gcx.clone(), post),
+            abort_registration,
+        ).await;
+        {
+            let mut pending_completions = self.pending_completions.write().await;
+            if pending_completions.get(&key).is_some_and(|h| Arc::ptr_eq(h, &abort_handle)) {
+                pending_completions.remove(&key);
+            }
+        }
+        let res = match res_maybe {
+            Ok(res) => res,
+            Err(Aborted) => {
+                info!("{} {} cancelled by a newer request", uri, kind);
+                return Err(Error::request_cancelled());
+            }
+        };
+        let resp = res.map_err(scratch_error_to_jsonrpc)?;
+        let status = resp.status();
+        let body_bytes = hyper::body::to_bytes(resp.into_body()).await.map_err(internal_error)?;
+        if !status.is_success() {
+            let detail = serde_json::from_slice::<serde_json::Value>(&body_bytes).ok()
+                .and_then(|v| v.get("detail").and_then(|d| d.as_str()).map(|d| d.to_string()))
+                .unwrap_or_else(|| String::from_utf8_lossy(&body_bytes).to_string());
+            return Err(scratch_error_to_jsonrpc(ScratchError::new(status, detail)));
+        }
+        let value = serde_json::from_slice::<CompletionRes>(&body_bytes).map_err(internal_error)?;
 
         Ok(value)
     }
 
     //3.18 textDocument/inlineCompletion handler
     pub async fn get_inline_completions(&self, params: InlineCompletionParams) -> Result<InlineCompletionList>{
-
+        // Automatic triggers happen while typing, keep them short. Explicit invoke means the user wants a block of code.
+        let multiline = params.context.trigger_kind == InlineCompletionTriggerKind::Invoked;
         let completion_params = CompletionParams1{
-            text_document_position : params.text_document_position,
-            parameters : RequestParams{
-                max_new_tokens : 50,
-                temperature: 0.2
-            },
-            multiline:true
+            text_document_position : params.text_document_position.clone(),
+            parameters : RequestParams::default(),
+            multiline,
+            text: None,
+        };
+        let mut post = self.flat_params_to_code_completion_post(&completion_params).await?;
+        let cursor = params.text_document_position.position;
+        let encoding = self.position_encoding.read().await.clone();
+        // Items extend the text of the item selected in the completion widget, and replace the same range
+        let (prefix, replace_range) = match &params.context.selected_completion_info {
+            Some(selected) => {
+                apply_selected_completion_info(&mut post, selected, encoding.as_str());
+                (selected.text.clone(), Range { start: selected.range.start, end: cursor.max(selected.range.end) })
+            }
+            None => (String::new(), Range { start: cursor, end: cursor }),
         };
 
-        let completion_res = self.get_completions(completion_params).await?;
+        let completion_res = self.code_completion_post_to_res(&mut post, "textDocument/inlineCompletion").await?;
 
-        let choices: Vec<InlineCompletionItem> = completion_res.choices.iter().map(|s|  {
-            InlineCompletionItem{
-                insert_text : s.code_completion.clone(),
-                command: None,
-                filter_text: Some(s.code_completion.clone()),
-                insert_text_format: Some(InsertTextFormat::PlainText)
-            }
-        }).collect();
+        let choices: Vec<InlineCompletionItem> = completion_res.choices.iter()
+            .filter(|s| !s.code_completion.is_empty())
+            .map(|s| {
+                let insert_text = format!("{}{}", prefix, s.code_completion);
+                InlineCompletionItem{
+                    insert_text : insert_text.clone(),
+                    command: None,
+                    filter_text: Some(insert_text),
+                    range: Some(replace_range),
+                    insert_text_format: Some(InsertTextFormat::PlainText)
+                }
+            }).collect();
 
         Ok(InlineCompletionList{
             items: choices,
         })
     }
   
+    // Streams deltas as refact/chatDelta notifications, returns the whole message at the end.
+    // To stop generation, send $/cancelRequest: tower_lsp drops this future, that drops the upstream stream.
+    pub async fn chat(&self, params: ChatParams) -> Result<ChatRes> {
+        let mut chat_post = ChatPost {
+            messages: params.messages,
+            parameters: params.parameters,
+            model: params.model,
+            scratchpad: params.scratchpad,
+            stream: Some(true),
+        };
+        self.run_chat(&mut chat_post, Some(params.chat_id)).await
+    }
+
+    // Collects the whole answer, sends refact/chatDelta notifications along the way if chat_id is given
+    async fn run_chat(&self, chat_post: &mut ChatPost, chat_id: Option<String>) -> Result<ChatRes> {
+        let resp = handle_v1_chat(self.gcx.clone(), chat_post).await.map_err(scratch_error_to_jsonrpc)?;
+        let mut reader = RestreamReader::new(resp.into_body());
+        let mut answer = ChatAnswer::new(&chat_post.model);
+        while let Some(data) = reader.next().await {
+            let data = data.map_err(|e| internal_error(format!("chat: {}", e)))?;
+            answer.add_chunk(&data);
+            if let Some(chat_id) = &chat_id {
+                self.client.send_notification::<ChatDelta>(ChatDeltaParams {
+                    chat_id: chat_id.clone(),
+                    data,
+                }).await;
+            }
+        }
+        Ok(ChatRes {
+            chat_id: chat_id.unwrap_or_default(),
+            message: ChatMessage { role: answer.role, content: answer.content },
+            finish_reason: answer.finish_reason,
+            model: answer.model,
+        })
+    }
+
+    async fn selection_whole_lines(&self, uri: &Url, range: &Range) -> Result<(String, Range, String)> {
+        // Code actions work on whole lines, that makes the model's job and the diff simpler
+        let document_map = self.document_map.read().await;
+        let document = document_map.get(uri.as_str())
+            .ok_or_else(|| Error::invalid_params(format!("document {} is not open", uri)))?;
+        let n_lines = document.text.len_lines();
+        let start_line = (range.start.line as usize).min(n_lines - 1);
+        let mut end_line = (range.end.line as usize).min(n_lines - 1);
+        if range.end.character > 0 || end_line == start_line {
+            end_line += 1;
+        }
+        let start_char = document.text.line_to_char(start_line);
+        let end_char = if end_line < n_lines { document.text.line_to_char(end_line) } else { document.text.len_chars() };
+        // The last line without "\n" has no next line to point at, the end is the end of that line then
+        let end = if end_line < n_lines {
+            Position { line: end_line as u32, character: 0 }
+        } else {
+            let encoding = self.position_encoding.read().await.clone();
+            let last_line = document.text.line(n_lines - 1);
+            let character = position_encoding::char_to_units_in_line(last_line, last_line.len_chars(), encoding.as_str());
+            Position { line: (n_lines - 1) as u32, character: character as u32 }
+        };
+        let whole_lines = Range {
+            start: Position { line: start_line as u32, character: 0 },
+            end,
+        };
+        Ok((document.text.slice(start_char..end_char).to_string(), whole_lines, document.language_id.clone()))
+    }
+
+    async fn ask_chat_about_code(&self, question: String) -> Result<String> {
+        let mut chat_post = ChatPost {
+            messages: vec![ChatMessage { role: "user".to_string(), content: question }],
+            parameters: SamplingParameters::default(),
+            model: "".to_string(),
+            scratchpad: "".to_string(),
+            stream: Some(true),
+        };
+        let chat_res = self.run_chat(&mut chat_post, None).await?;
+        Ok(chat_res.message.content)
+    }
+
+    async fn run_code_action_command(&self, command: &str, arguments: &[serde_json::Value]) -> Result<Option<serde_json::Value>> {
+        let uri: Url = arguments.first().cloned().and_then(|x| serde_json::from_value(x).ok())
+            .ok_or_else(|| Error::invalid_params("first argument must be document uri"))?;
+        let range: Range = arguments.get(1).cloned().and_then(|x| serde_json::from_value(x).ok())
+            .ok_or_else(|| Error::invalid_params("second argument must be range"))?;
+        let (code, whole_lines, language_id) = self.selection_whole_lines(&uri, &range).await?;
+        if command == COMMAND_EXPLAIN {
+            let explanation = self.ask_chat_about_code(format!(
+                "Explain what this {} code does:\n```\n{}```", language_id, code
+            )).await?;
+            self.client.show_message(MessageType::INFO, &explanation).await;
+            return Ok(Some(serde_json::json!({"explanation": explanation})));
+        }
+        let question = if command == COMMAND_ADD_DOCSTRING {
+            format!("Add a docstring to this {} code, change nothing else. Reply with the whole updated code in one code block.\n```\n{}```", language_id, code)
+        } else {
+            let instruction = arguments.get(2).and_then(|x| x.as_str()).filter(|x| !x.is_empty()).unwrap_or(DEFAULT_REFACTOR_INSTRUCTION);
+            format!("{}\nReply with the whole updated code in one code block.\n```\n{}```", instruction, code)
+        };
+        let answer = self.ask_chat_about_code(question).await?;
+        // Without a code block the answer is prose, it must not replace the user's code
+        let mut new_code = match first_code_block(&answer) {
+            Some(new_code) => new_code,
+            None => {
+                self.client.show_message(MessageType::WARNING, format!("refact: the model didn't reply with code:\n{}", answer)).await;
+                return Ok(None);
+            }
+        };
+        if code.ends_with('\n') && !new_code.ends_with('\n') {
+            new_code.push('\n');
+        } else if !code.ends_with('\n') && new_code.ends_with('\n') {
+            new_code.pop();
+        }
+        let edits = diff_to_text_edits(&code, &new_code, &whole_lines);
+        if edits.is_empty() {
+            self.client.show_message(MessageType::INFO, "refact: no changes suggested").await;
+            return Ok(None);
+        }
+        // The model takes seconds, if the user typed in these lines meanwhile, the line numbers point to other code
+        let unchanged = self.selection_whole_lines(&uri, &range).await
+            .is_ok_and(|(current_code, current_lines, _)| current_code == code && current_lines == whole_lines);
+        if !unchanged {
+            self.client.show_message(MessageType::WARNING, "refact: the code changed while waiting for the model, nothing applied").await;
+            return Ok(None);
+        }
+        let workspace_edit = WorkspaceEdit {
+            changes: Some(HashMap::from([(uri, edits)])),
+            ..Default::default()
+        };
+        let applied = self.client.apply_edit(workspace_edit.clone()).await?;
+        info!("{} edit applied: {}", command, applied.applied);
+        Ok(Some(serde_json::json!(workspace_edit)))
+    }
+
     pub async fn test_if_head_tail_equal_return_added_text(&self, params: TestHeadTailAddedText) -> Result<TestHeadTailAddedTextRes> {
         let (is_valid, grey_corrected) = telemetry::utils::if_head_tail_equal_return_added_text(
             &params.text_a, &params.text_b, &params.orig_grey_text
@@ -266,6 +588,16 @@ impl Backend {
         }
         Ok(TestHeadTailAddedTextRes{is_valid, grey_corrected, unchanged_percentage})
     }
+
+    pub async fn snippet_accepted(&self, params: SnippetTelemetryParams) {
+        let success = telemetry::snippets_collection::snippet_accepted(self.gcx.clone(), params.snippet_telemetry_id).await;
+        info!("snippet {} accepted, found={}", params.snippet_telemetry_id, success);
+    }
+
+    pub async fn snippet_rejected(&self, params: SnippetTelemetryParams) {
+        let success = telemetry::snippets_collection::snippet_rejected(self.gcx.clone(), params.snippet_telemetry_id).await;
+        info!("snippet {} rejected, found={}", params.snippet_telemetry_id, success);
+    }
  }
 
 
@@ -275,11 +607,16 @@ impl LanguageServer for Backend {
         *self.workspace_folders.write().await = params.workspace_folders;
         info!("LSP client_info {:?}", params.client_info);
         info!("LSP workspace_folders {:?}", self.workspace_folders);
+        let encoding = negotiate_position_encoding(&params.capabilities);
+        info!("LSP position encoding {}", encoding.as_str());
+        *self.position_encoding.write().await = encoding.clone();
+        *self.work_done_progress.write().await = params.capabilities.window.as_ref()
+            .and_then(|w| w.work_done_progress).unwrap_or(false);
 
         let completion_options: CompletionOptions;
         completion_options = CompletionOptions {
             resolve_provider: Some(false),
-            trigger_characters: Some(vec![".(".to_owned()]),
+            trigger_characters: Some(TRIGGER_CHARACTERS.iter().map(|x| x.to_string()).collect()),
             all_commit_characters: None,
             work_done_progress_options: WorkDoneProgressOptions { work_done_progress: Some(false) },
             completion_item: None,
@@ -290,10 +627,23 @@ impl LanguageServer for Backend {
                 version: Some(VERSION.to_owned()),
             }),
             capabilities: ServerCapabilities {
+                position_encoding: Some(encoding),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
                 completion_provider: Some(completion_options),
+                code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+                    code_action_kinds: Some(vec![CodeActionKind::REFACTOR, CodeActionKind::REFACTOR_REWRITE]),
+                    work_done_progress_options: WorkDoneProgressOptions { work_done_progress: None },
+                    resolve_provider: Some(false),
+                })),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![COMMAND_EXPLAIN.to_string(), COMMAND_ADD_DOCSTRING.to_string(), COMMAND_REFACTOR.to_string()],
+                    work_done_progress_options: WorkDoneProgressOptions { work_done_progress: None },
+                }),
+                // lsp_types doesn't know about 3.18 inlineCompletionProvider yet, inline_completion_provider_to_top_level()
+                // copies it to where 3.18 clients look for it
+                experimental: Some(serde_json::json!({"inlineCompletionProvider": true})),
                 ..Default::default()
             },
         })
@@ -304,39 +654,92 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "rust LSP received initialized()")
             .await;
         let _ = info!("rust LSP received initialized()");
+        let (mut events, status) = {
+            let cx_locked = self.gcx.read().await;
+            (cx_locked.status_events.subscribe(), cx_locked.status.clone())
+        };
+        self.client.send_notification::<RefactStatusNotification>(status).await;
+        let client = self.client.clone();
+        let work_done_progress = *self.work_done_progress.read().await;
+        let handle = tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                forward_status_event(&client, work_done_progress, event).await;
+            }
+        });
+        if let Some(old) = self.status_forwarder.lock().unwrap().replace(handle) {
+            old.abort();
+        }
     }
 
-    // TODO:
-    // textDocument/didClose
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        let settings_json = match runtime_settings_json(params.settings) {
+            Some(settings_json) => settings_json,
+            None => {
+                info!("didChangeConfiguration has no refact settings, ignoring");
+                return;
+            }
+        };
+        match serde_json::from_value::<RuntimeSettings>(settings_json) {
+            Ok(settings) => {
+                global_context::apply_runtime_settings(self.gcx.clone(), settings).await;
+            }
+            Err(e) => {
+                error!("didChangeConfiguration settings problem: {}", e);
+                self.client
+                    .show_message(MessageType::ERROR, format!("refact-lsp settings problem: {}", e))
+                    .await;
+            }
+        }
+    }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         let rope = ropey::Rope::from_str(&params.text_document.text);
         let uri = params.text_document.uri.to_string();
-        *self
-            .document_map
-            .write()
-            .await
-            .entry(uri.clone())
-            .or_insert(Document::new("unknown".to_owned(), Rope::new())) =
-            Document::new(params.text_document.language_id, rope);
+        let max_bytes = self.gcx.read().await.cmdline.lsp_documents_max_bytes;
+        let mut document_map = self.document_map.write().await;
+        document_map.insert(uri.clone(), Document::new(params.text_document.language_id, rope));
+        evict_documents_over_limit(&mut document_map, max_bytes, &uri);
         info!("{uri} opened");
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let t0 = Instant::now();
-        let rope = ropey::Rope::from_str(&params.content_changes[0].text);
         let uri = params.text_document.uri.to_string();
-        let mut document_map = self.document_map.write().await;
-        let doc = document_map
-            .entry(uri.clone())
-            .or_insert(Document::new("unknown".to_owned(), Rope::new()));
-        doc.text = rope;
-        info!("{} changed, save time: {:?}", uri, t0.elapsed());
+        let max_bytes = self.gcx.read().await.cmdline.lsp_documents_max_bytes;
+        let encoding = self.position_encoding.read().await.clone();
+        let text = {
+            let mut document_map = self.document_map.write().await;
+            if !document_map.contains_key(&uri) && params.content_changes.first().is_none_or(|c| c.range.is_some()) {
+                // Evicted because of the memory limit, or never opened: range edits have nothing to apply to
+                drop(document_map);
+                error!("{} changed, but it's not in the document map, ignoring incremental change", uri);
+                self.client.log_message(MessageType::WARNING, format!(
+                    "refact-lsp doesn't have {} in memory (over --lsp-documents-max-bytes?), no completions there until the file is reopened", uri
+                )).await;
+                return;
+            }
+            let doc = document_map
+                .entry(uri.clone())
+                .or_insert(Document::new("unknown".to_owned(), Rope::new()));
+            for change in params.content_changes.iter() {
+                apply_content_change(&mut doc.text, change, encoding.as_str());
+            }
+            doc.last_changed = Instant::now();
+            let text = doc.text.to_string();
+            evict_documents_over_limit(&mut document_map, max_bytes, &uri);
+            text
+        };
+        info!("{} changed, {} edits, save time: {:?}", uri, params.content_changes.len(), t0.elapsed());
         let t1 = Instant::now();
         telemetry::snippets_collection::sources_changed(
             self.gcx.clone(),
             &uri,
-            &params.content_changes[0].text,
+            &text,
         ).await;
         info!("{} changed, telemetry time: {:?}", uri, t1.elapsed());
     }
@@ -354,39 +757,409 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "{refact-lsp} file closed")
             .await;
         let uri = params.text_document.uri.to_string();
+        let document_maybe = self.document_map.write().await.remove(&uri);
+        self.pending_completions.write().await.retain(|(pending_uri, _), pending| {
+            if *pending_uri == uri {
+                pending.abort();
+            }
+            *pending_uri != uri
+        });
+        if let Some(document) = document_maybe {
+            telemetry::snippets_collection::sources_closed(
+                self.gcx.clone(),
+                &uri,
+                &document.text.to_string(),
+            ).await;
+        }
         info!("{uri} closed");
     }
 
     async fn shutdown(&self) -> Result<()> {
         let _ = info!("shutdown");
+        if let Some(handle) = self.status_forwarder.lock().unwrap().take() {
+            handle.abort();
+        }
         Ok(())
     }
 
-    async fn completion(&self, _: CompletionParams) -> Result<Option<CompletionResponse>> {
-        info!("asked for completion");
-        Ok(Some(CompletionResponse::Array(vec![
-            CompletionItem::new_simple("Hello".to_string(), "Some detail".to_string()),
-            CompletionItem::new_simple("Bye".to_string(), "More detail".to_string()),
-        ])))
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        // Actions are cheap to list, the model is called in execute_command when the user picks one
+        if params.range.start == params.range.end {
+            return Ok(None);
+        }
+        let arguments = vec![serde_json::json!(params.text_document.uri), serde_json::json!(params.range)];
+        let actions = [
+            (COMMAND_EXPLAIN, "Refact: Explain", CodeActionKind::REFACTOR),
+            (COMMAND_ADD_DOCSTRING, "Refact: Add docstring", CodeActionKind::REFACTOR_REWRITE),
+            (COMMAND_REFACTOR, "Refact: Refactor", CodeActionKind::REFACTOR_REWRITE),
+        ];
+        let only = params.context.only.unwrap_or_default();
+        let response: CodeActionResponse = actions.into_iter()
+            .filter(|(_, _, kind)| only.is_empty() || only.iter().any(|o| kind.as_str().starts_with(o.as_str())))
+            .map(|(command, title, kind)| CodeActionOrCommand::CodeAction(CodeAction {
+                title: title.to_string(),
+                kind: Some(kind),
+                command: Some(Command {
+                    title: title.to_string(),
+                    command: command.to_string(),
+                    arguments: Some(arguments.clone()),
+                }),
+                ..Default::default()
+            }))
+            .collect();
+        Ok(Some(response))
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<serde_json::Value>> {
+        info!("execute command {}", params.command);
+        match params.command.as_str() {
+            COMMAND_EXPLAIN | COMMAND_ADD_DOCSTRING | COMMAND_REFACTOR => {
+                self.run_code_action_command(&params.command, &params.arguments).await
+            }
+            _ => Err(Error::invalid_params(format!("unknown command {}", params.command))),
+        }
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        if let Some(context) = &params.context {
+            if context.trigger_kind == CompletionTriggerKind::TRIGGER_CHARACTER {
+                let trigger_character = context.trigger_character.clone().unwrap_or_default();
+                if !TRIGGER_CHARACTERS.contains(&trigger_character.as_str()) {
+                    info!("completion ignored, trigger character {:?} is not ours", trigger_character);
+                    return Ok(None);
+                }
+            }
+        }
+        let position = params.text_document_position.position;
+        let encoding = self.position_encoding.read().await.clone();
+        let (word_prefix, word_start) = {
+            let document_map = self.document_map.read().await;
+            let document = match document_map.get(params.text_document_position.text_document.uri.as_str()) {
+                Some(document) => document,
+                None => return Ok(None),
+            };
+            word_before_cursor(&document.text, &position, encoding.as_str())
+        };
+        let completion_params = CompletionParams1 {
+            text_document_position: params.text_document_position.clone(),
+            parameters: RequestParams::default(),
+            multiline: false,
+            text: None,
+        };
+        let completion_res = self.get_completions_of_kind(completion_params, "textDocument/completion").await?;
+        // Replace the word under cursor as well, so the editor can filter the list by what the user has already typed
+        let replace_range = Range { start: word_start, end: position };
+        let items: Vec<CompletionItem> = completion_res.choices.iter()
+            .filter(|c| !c.code_completion.trim().is_empty())
+            .map(|c| {
+                let new_text = format!("{}{}", word_prefix, c.code_completion);
+                CompletionItem {
+                    label: new_text.lines().next().unwrap_or("").trim().to_string(),
+                    kind: Some(CompletionItemKind::TEXT),
+                    detail: Some(completion_res.model.clone()),
+                    filter_text: Some(new_text.clone()),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit { range: replace_range, new_text })),
+                    data: Some(serde_json::json!({"snippet_telemetry_id": completion_res.snippet_telemetry_id})),
+                    ..Default::default()
+                }
+            }).collect();
+        info!("completion returns {} items", items.len());
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+}
+
+fn word_before_cursor(rope: &Rope, position: &Position, encoding: &str) -> (String, Position) {
+    let cursor = position_to_char_idx(rope, position, encoding);
+    let line_start = rope.line_to_char(rope.char_to_line(cursor));
+    let before: Vec<char> = rope.slice(line_start..cursor).chars().collect();
+    let word_len = before.iter().rev().take_while(|c| c.is_alphanumeric() || **c == '_').count();
+    let (line, character) = position_encoding::char_idx_to_position(rope, cursor - word_len, encoding);
+    (before[before.len() - word_len..].iter().collect(), Position { line: line as u32, character: character as u32 })
+}
+
+fn apply_selected_completion_info(post: &mut CodeCompletionPost, selected: &SelectedCompletionInfo, encoding: &str) {
+    // Pretend the selected item is already accepted: put its text into the source and move the cursor after it
+    let source = match post.inputs.sources.get_mut(&post.inputs.cursor.file) {
+        Some(source) => source,
+        None => return,
+    };
+    let mut rope = Rope::from_str(source);
+    let start = position_to_char_idx(&rope, &selected.range.start, encoding);
+    let end = position_to_char_idx(&rope, &selected.range.end, encoding).max(start);
+    rope.remove(start..end);
+    rope.insert(start, &selected.text);
+    let new_cursor = start + selected.text.chars().count();
+    let line = rope.char_to_line(new_cursor);
+    post.inputs.cursor.line = line as i32;
+    post.inputs.cursor.character = (new_cursor - rope.line_to_char(line)) as i32;
+    *source = rope.to_string();
+}
+
+fn runtime_settings_json(settings: serde_json::Value) -> Option<serde_json::Value> {
+    // Plugins either send settings as is, or wrapped into {"refact": {...}}. Clients in pull mode send null,
+    // and settings of other extensions must not reset ours to defaults
+    if let Some(refact) = settings.get("refact") {
+        return refact.is_object().then(|| refact.clone());
+    }
+    let known_keys = serde_json::to_value(RuntimeSettings::default()).unwrap();
+    let has_known_key = settings.as_object()
+        .is_some_and(|obj| obj.keys().any(|k| known_keys.get(k).is_some()));
+    has_known_key.then_some(settings)
+}
+
+fn first_code_block(answer: &str) -> Option<String> {
+    // Models like to wrap code into ```lang ... ```, take what's inside, None if there's no block
+    let mut lines = answer.lines().skip_while(|l| !l.trim_start().starts_with("```"));
+    lines.next()?;
+    let mut code = String::new();
+    for line in lines.take_while(|l| !l.trim_start().starts_with("```")) {
+        code.push_str(line);
+        code.push('\n');
+    }
+    Some(code)
+}
+
+fn diff_to_text_edits(old_text: &str, new_text: &str, whole_lines: &Range) -> Vec<TextEdit> {
+    // Line based diff, each changed group of lines becomes one edit, so the editor keeps markers in the unchanged code
+    let diff = TextDiff::from_lines(old_text, new_text);
+    let new_lines: Vec<&str> = diff.new_slices().to_vec();
+    let n_old_lines = diff.old_slices().len();
+    // After the last old line is the end of the selection, that's not always the start of a line
+    let line_position = |old_line: usize| if old_line < n_old_lines {
+        Position { line: whole_lines.start.line + old_line as u32, character: 0 }
+    } else {
+        whole_lines.end
+    };
+    let mut edits = vec![];
+    for op in diff.ops() {
+        if op.tag() == DiffTag::Equal {
+            continue;
+        }
+        let old_range = op.old_range();
+        edits.push(TextEdit {
+            range: Range {
+                start: line_position(old_range.start),
+                end: line_position(old_range.end),
+            },
+            new_text: new_lines[op.new_range()].concat(),
+        });
+    }
+    edits
+}
+
+fn evict_documents_over_limit(
+    document_map: &mut HashMap<String, Document>,
+    max_bytes: usize,
+    keep_uri: &String,
+) {
+    // Drops documents the user didn't touch for the longest time, the one just opened or changed always stays
+    let mut total_bytes: usize = document_map.values().map(|d| d.text.len_bytes()).sum();
+    while total_bytes > max_bytes {
+        let oldest_uri = document_map.iter()
+            .filter(|(uri, _)| *uri != keep_uri)
+            .min_by_key(|(_, d)| d.last_changed)
+            .map(|(uri, _)| uri.clone());
+        match oldest_uri {
+            Some(uri) => {
+                let document = document_map.remove(&uri).unwrap();
+                total_bytes -= document.text.len_bytes();
+                info!("{} evicted from document map, {} bytes total is over the limit {}", uri, total_bytes, max_bytes);
+            }
+            None => break,
+        }
+    }
+}
+
+fn open_tabs_context(
+    document_map: &HashMap<String, Document>,
+    current_uri: &str,
+    current_text: &Rope,
+    budget_tokens: usize,
+) -> HashMap<String, String> {
+    // Other open documents go to sources for multi-file scratchpads: imported by the current file first,
+    // then the same language, then the most recently edited. Tokens are estimated as 4 bytes per token,
+    // because the tokenizer depends on the model that is not known yet.
+    const BYTES_PER_TOKEN: usize = 4;
+    const IMPORT_SCAN_LINES: usize = 300;   // imports are at the top, this runs on every keystroke
+    let mut result = HashMap::new();
+    if budget_tokens == 0 {
+        return result;
+    }
+    let current_language = document_map.get(current_uri).map(|d| d.language_id.clone());
+    // A line is one rope chunk almost always, borrowed without copying
+    let import_lines: Vec<Cow<str>> = current_text.lines()
+        .take(IMPORT_SCAN_LINES)
+        .map(|line| line.as_str().map(Cow::Borrowed).unwrap_or_else(|| Cow::Owned(line.to_string())))
+        .filter(|line| {
+            let line = line.trim_start();
+            ["import ", "from ", "#include", "use ", "mod ", "require"].iter().any(|k| line.starts_with(k))
+                || line.contains("require(") || line.contains("import(")
+        })
+        .collect();
+    let mut candidates: Vec<(bool, bool, Instant, &String, &Document)> = document_map.iter()
+        .filter(|(uri, d)| uri.as_str() != current_uri && d.text.len_chars() > 0)
+        .map(|(uri, d)| {
+            let same_language = match &current_language {
+                Some(language_id) => *language_id == d.language_id,
+                None => file_extension(uri) == file_extension(current_uri),
+            };
+            let stem = file_stem(uri);
+            let imported = !stem.is_empty() && import_lines.iter().any(|line| {
+                line.split(|c: char| !c.is_alphanumeric() && c != '_').any(|word| word == stem)
+            });
+            (imported, same_language, d.last_changed, uri, d)
+        })
+        .collect();
+    candidates.sort_by_key(|c| std::cmp::Reverse((c.0, c.1, c.2)));
+    let mut budget_bytes = budget_tokens * BYTES_PER_TOKEN;
+    for (_, _, _, uri, document) in candidates {
+        let bytes = document.text.len_bytes();
+        if bytes > budget_bytes {
+            continue;
+        }
+        budget_bytes -= bytes;
+        result.insert(uri.clone(), document.text.to_string());
+    }
+    result
+}
+
+fn file_name(uri: &str) -> &str {
+    uri.rsplit('/').next().unwrap_or(uri)
+}
+
+fn file_stem(uri: &str) -> &str {
+    let name = file_name(uri);
+    name.split('.').next().unwrap_or(name)
+}
+
+fn file_extension(uri: &str) -> &str {
+    let name = file_name(uri);
+    name.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("")
+}
+
+fn negotiate_position_encoding(capabilities: &ClientCapabilities) -> PositionEncodingKind {
+    // Client lists encodings in the order of preference, UTF-16 is the default that must always work
+    let client_encodings = capabilities.general.as_ref()
+        .and_then(|g| g.position_encodings.clone())
+        .unwrap_or_default();
+    client_encodings.into_iter()
+        .find(|e| position_encoding::SUPPORTED.contains(&e.as_str()))
+        .unwrap_or(PositionEncodingKind::UTF16)
+}
+
+fn position_to_char_idx(rope: &Rope, position: &Position, encoding: &str) -> usize {
+    // Clamps out of range positions to the end of line / end of document, like editors do
+    position_encoding::position_to_char_idx(rope, position.line as usize, position.character as usize, encoding)
+}
+
+fn apply_content_change(rope: &mut Rope, change: &TextDocumentContentChangeEvent, encoding: &str) {
+    // Changes within one notification are applied in order, each range is relative to the text after the previous change
+    match change.range {
+        Some(range) => {
+            let start = position_to_char_idx(rope, &range.start, encoding);
+            let end = position_to_char_idx(rope, &range.end, encoding).max(start);
+            rope.remove(start..end);
+            rope.insert(start, &change.text);
+        }
+        None => {
+            *rope = Rope::from_str(&change.text);
+        }
+    }
+}
+
+async fn forward_status_event(
+    client: &tower_lsp::Client,
+    work_done_progress: bool,
+    event: global_context::StatusEvent,
+) {
+    // Progress only goes to clients that can show it, refact/status goes to everyone
+    match event {
+        global_context::StatusEvent::ProgressBegin { token, title } => {
+            if !work_done_progress {
+                return;
+            }
+            let token = NumberOrString::String(token);
+            let created = client.send_request::<request::WorkDoneProgressCreate>(
+                WorkDoneProgressCreateParams { token: token.clone() }
+            ).await;
+            if let Err(e) = created {
+                info!("workDoneProgress/create failed: {:?}", e);
+                return;
+            }
+            client.send_notification::<notification::Progress>(ProgressParams {
+                token,
+                value: ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                    title,
+                    cancellable: Some(false),
+                    message: None,
+                    percentage: None,
+                })),
+            }).await;
+        }
+        global_context::StatusEvent::ProgressEnd { token, message } => {
+            if !work_done_progress {
+                return;
+            }
+            client.send_notification::<notification::Progress>(ProgressParams {
+                token: NumberOrString::String(token),
+                value: ProgressParamsValue::WorkDone(WorkDoneProgress::End(WorkDoneProgressEnd {
+                    message: Some(message),
+                })),
+            }).await;
+        }
+        global_context::StatusEvent::StatusChanged(status) => {
+            client.send_notification::<RefactStatusNotification>(status).await;
+        }
     }
 }
 
+type LspResponseFn = fn(Option<tower_lsp::jsonrpc::Response>) -> Option<tower_lsp::jsonrpc::Response>;
+type RefactLspService = tower::util::MapResponse<LspService<Backend>, LspResponseFn>;
+
+fn inline_completion_provider_to_top_level(response: Option<tower_lsp::jsonrpc::Response>) -> Option<tower_lsp::jsonrpc::Response> {
+    // Only the initialize result has capabilities, other responses pass through untouched
+    let (id, mut result) = response?.into_parts();
+    if let Ok(value) = &mut result {
+        if let Some(capabilities) = value.get_mut("capabilities").and_then(|c| c.as_object_mut()) {
+            let provider = capabilities.get("experimental").and_then(|e| e.get("inlineCompletionProvider")).cloned();
+            if let Some(provider) = provider {
+                capabilities.insert("inlineCompletionProvider".to_string(), provider);
+            }
+        }
+    }
+    Some(tower_lsp::jsonrpc::Response::from_parts(id, result))
+}
+
 fn build_lsp_service(
     gcx: Arc<ARwLock<global_context::GlobalContext>>,
-) -> (LspService::<Backend>, ClientSocket) {
+) -> (RefactLspService, ClientSocket) {
     let (lsp_service, socket) = LspService::build(|client| Backend {
         gcx,
         client,
         document_map: Arc::new(ARwLock::new(HashMap::new())),
         workspace_folders: Arc::new(ARwLock::new(None)),
+        position_encoding: Arc::new(ARwLock::new(PositionEncodingKind::UTF16)),
+        pending_completions: Arc::new(ARwLock::new(HashMap::new())),
+        work_done_progress: Arc::new(ARwLock::new(false)),
+        status_forwarder: Arc::new(std::sync::Mutex::new(None)),
     })
         .custom_method("refact/getCompletions", Backend::get_completions)
+        .custom_method("refact/promptPreview", Backend::prompt_preview)
         //tower_lsp does not currently support 3.18 textDocument/inlineCompletion 
         //so we add it as a custom method for now
         .custom_method("textDocument/inlineCompletion", Backend::get_inline_completions)
+        .custom_method("refact/chat", Backend::chat)
+        .custom_method("refact/snippetAccepted", Backend::snippet_accepted)
+        .custom_method("refact/snippetRejected", Backend::snippet_rejected)
         .custom_method("refact/test_if_head_tail_equal_return_added_text", Backend::test_if_head_tail_equal_return_added_text)
         .finish();
-    (lsp_service, socket)
+    (lsp_service.map_response(inline_completion_provider_to_top_level as LspResponseFn), socket)
+}
+
+async fn lsp_clients_count_change(gcx: SharedGlobalContext, delta: i32) -> usize {
+    let mut gcx_locked = gcx.write().await;
+    gcx_locked.lsp_clients_count = (gcx_locked.lsp_clients_count as i32 + delta).max(0) as usize;
+    gcx_locked.lsp_clients_count
 }
 
 pub fn spawn_lsp_task(
@@ -400,14 +1173,19 @@ pub fn spawn_lsp_task(
             let listener: TcpListener = TcpListener::bind(&addr).await.unwrap();
             info!("LSP listening on {}", listener.local_addr().unwrap());
             loop {
-                // possibly wrong code, look at
-                // tower-lsp-0.20.0/examples/tcp.rs
                 match listener.accept().await {
                     Ok((s, addr)) => {
-                        info!("LSP new client connection from {}", addr);
-                        let (read, write) = tokio::io::split(s);
-                        let (lsp_service, socket) = build_lsp_service(gcx_t.clone());
-                        tower_lsp::Server::new(read, write, socket).serve(lsp_service).await;
+                        // Each IDE window gets its own task and document map, caches and telemetry are shared via gcx
+                        let gcx_c = gcx_t.clone();
+                        tokio::spawn(async move {
+                            let clients_count = lsp_clients_count_change(gcx_c.clone(), 1).await;
+                            info!("LSP new client connection from {}, {} clients connected", addr, clients_count);
+                            let (read, write) = tokio::io::split(s);
+                            let (lsp_service, socket) = build_lsp_service(gcx_c.clone());
+                            tower_lsp::Server::new(read, write, socket).serve(lsp_service).await;
+                            let clients_count = lsp_clients_count_change(gcx_c.clone(), -1).await;
+                            info!("LSP client {} disconnected, {} clients connected", addr, clients_count);
+                        });
                     }
                     Err(e) => {
                         error!("Error accepting client connection: {}", e);
@@ -422,11 +1200,126 @@ pub fn spawn_lsp_task(
         return Some(tokio::spawn( async move {
             let stdin = tokio::io::stdin();
             let stdout = tokio::io::stdout();
+            lsp_clients_count_change(gcx_t.clone(), 1).await;
             let (lsp_service, socket) = build_lsp_service(gcx_t.clone());
             tower_lsp::Server::new(stdin, stdout, socket).serve(lsp_service).await;
+            lsp_clients_count_change(gcx_t.clone(), -1).await;
             info!("LSP loop exit");
         }));
     }
     
     None
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(start: (u32, u32), end: (u32, u32), text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply_content_change_utf16_surrogate_pair() {
+        let mut rope = Rope::from_str("a😀b\n");
+        // The emoji takes columns 1..3 in UTF-16
+        apply_content_change(&mut rope, &change((0, 1), (0, 3), "x"), position_encoding::UTF16);
+        assert_eq!(rope.to_string(), "axb\n");
+    }
+
+    #[test]
+    fn test_apply_content_change_utf8_cjk() {
+        let mut rope = Rope::from_str("日本語\n");
+        // Each char is 3 bytes
+        apply_content_change(&mut rope, &change((0, 3), (0, 6), "-"), position_encoding::UTF8);
+        assert_eq!(rope.to_string(), "日-語\n");
+        apply_content_change(&mut rope, &change((0, 2), (0, 2), "+"), position_encoding::UTF32);
+        assert_eq!(rope.to_string(), "日-+語\n");
+    }
+
+    #[test]
+    fn test_apply_content_change_out_of_range() {
+        let mut rope = Rope::from_str("ab\ncd");
+        // Past the end of line is the end of line, past the last line is the end of document
+        apply_content_change(&mut rope, &change((0, 100), (0, 100), "!"), position_encoding::UTF16);
+        assert_eq!(rope.to_string(), "ab!\ncd");
+        apply_content_change(&mut rope, &change((10, 0), (10, 0), "?"), position_encoding::UTF16);
+        assert_eq!(rope.to_string(), "ab!\ncd?");
+        // End before start removes nothing
+        apply_content_change(&mut rope, &change((1, 1), (0, 0), "_"), position_encoding::UTF16);
+        assert_eq!(rope.to_string(), "ab!\nc_d?");
+    }
+
+    #[test]
+    fn test_apply_content_change_multiple_incremental() {
+        let mut rope = Rope::from_str("fn main() {\n}\n");
+        // Each range is relative to the text after the previous change
+        let changes = [
+            change((0, 11), (0, 11), "\n    let s = \"😀\";"),
+            change((1, 15), (1, 15), "!"),
+            change((0, 3), (0, 7), "start"),
+        ];
+        for c in changes.iter() {
+            apply_content_change(&mut rope, c, position_encoding::UTF16);
+        }
+        assert_eq!(rope.to_string(), "fn start() {\n    let s = \"😀!\";\n}\n");
+    }
+
+    #[test]
+    fn test_diff_to_text_edits_last_line_without_newline() {
+        let whole_lines = Range::new(Position::new(3, 0), Position::new(4, 1));
+        let edits = diff_to_text_edits("a\nb", "a\nc", &whole_lines);
+        assert_eq!(edits, vec![TextEdit { range: Range::new(Position::new(4, 0), Position::new(4, 1)), new_text: "c".to_string() }]);
+        let edits = diff_to_text_edits("a\nb", "a\nb\nc", &whole_lines);
+        assert_eq!(edits, vec![TextEdit { range: Range::new(Position::new(4, 0), Position::new(4, 1)), new_text: "b\nc".to_string() }]);
+    }
+
+    #[test]
+    fn test_diff_to_text_edits_whole_lines() {
+        let whole_lines = Range::new(Position::new(3, 0), Position::new(5, 0));
+        let edits = diff_to_text_edits("a\nb\n", "a\nc\n", &whole_lines);
+        assert_eq!(edits, vec![TextEdit { range: Range::new(Position::new(4, 0), Position::new(5, 0)), new_text: "c\n".to_string() }]);
+    }
+
+    #[test]
+    fn test_runtime_settings_json() {
+        let wrapped = serde_json::json!({"refact": {"chat_model": "gpt-4"}});
+        assert_eq!(runtime_settings_json(wrapped), Some(serde_json::json!({"chat_model": "gpt-4"})));
+        let as_is = serde_json::json!({"chat_model": "gpt-4", "other": 1});
+        assert_eq!(runtime_settings_json(as_is.clone()), Some(as_is));
+        assert_eq!(runtime_settings_json(serde_json::Value::Null), None);
+        assert_eq!(runtime_settings_json(serde_json::json!("refact")), None);
+        assert_eq!(runtime_settings_json(serde_json::json!({"refact": null})), None);
+        assert_eq!(runtime_settings_json(serde_json::json!({"python": {"linting": true}})), None);
+    }
+
+    #[test]
+    fn test_open_tabs_context_imports_first() {
+        let mut document_map = HashMap::from([
+            ("file:///p/utils.py".to_string(), Document::new("python".to_string(), Rope::from_str("def helper(): pass\n"))),
+            ("file:///p/late.py".to_string(), Document::new("python".to_string(), Rope::from_str("def late(): pass\n"))),
+            ("file:///p/main.py".to_string(), Document::new("python".to_string(), Rope::new())),
+        ]);
+        document_map.get_mut("file:///p/late.py").unwrap().last_changed += std::time::Duration::from_secs(1);
+        // Room for one file only, 5 tokens is 20 bytes
+        let current = Rope::from_str("import utils\n");
+        let sources = open_tabs_context(&document_map, "file:///p/main.py", &current, 5);
+        assert_eq!(sources.keys().collect::<Vec<_>>(), vec!["file:///p/utils.py"]);
+        // Imports too far from the top are not looked at, late.py was changed last and wins
+        let current = Rope::from_str(&format!("{}import utils\n", "x = 1\n".repeat(1000)));
+        let sources = open_tabs_context(&document_map, "file:///p/main.py", &current, 5);
+        assert_eq!(sources.keys().collect::<Vec<_>>(), vec!["file:///p/late.py"]);
+    }
+
+    #[test]
+    fn test_apply_content_change_full_text() {
+        let mut rope = Rope::from_str("old");
+        apply_content_change(&mut rope, &TextDocumentContentChangeEvent { range: None, range_length: None, text: "new\n".to_string() }, position_encoding::UTF16);
+        assert_eq!(rope.to_string(), "new\n");
+    }
+}
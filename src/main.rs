@@ -18,6 +18,9 @@ mod cached_tokenizers;
 mod restream;
 mod custom_error;
 mod completion_cache;
+mod limits;
+mod metrics;
+mod position_encoding;
 mod telemetry;
 mod vecdb_search;
 mod lsp;
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+use std::time::Duration;
+
+use crate::completion_cache::CompletionCache;
+use crate::telemetry::telemetry_structs;
+
+// Served as Prometheus text format on /metrics. Counters live in memory only, they start from zero
+// with each process, that's what Prometheus expects.
+
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    pub buckets: [u64; LATENCY_BUCKETS.len()],   // not cumulative, summed up when rendered
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|b| seconds <= *b) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bucket, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bucket, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub http_requests: HashMap<(String, u16), u64>,   // (route, status)
+    pub http_latency: HashMap<String, Histogram>,
+    pub upstream_latency: HashMap<String, Histogram>,  // per model, errors included
+    pub upstream_errors: HashMap<String, u64>,
+    pub tokenizer_load_seconds: HashMap<String, f64>,  // download (if needed) and parse
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+pub fn http_request(metrics: &Arc<StdRwLock<Metrics>>, route: &str, status: u16, elapsed: Duration) {
+    let mut metrics_locked = metrics.write().unwrap();
+    *metrics_locked.http_requests.entry((route.to_string(), status)).or_insert(0) += 1;
+    metrics_locked.http_latency.entry(route.to_string()).or_default().observe(elapsed.as_secs_f64());
+}
+
+pub fn upstream_call(metrics: &Arc<StdRwLock<Metrics>>, model: &str, elapsed: Duration, success: bool) {
+    let mut metrics_locked = metrics.write().unwrap();
+    metrics_locked.upstream_latency.entry(model.to_string()).or_default().observe(elapsed.as_secs_f64());
+    if !success {
+        *metrics_locked.upstream_errors.entry(model.to_string()).or_insert(0) += 1;
+    }
+}
+
+pub fn tokenizer_loaded(metrics: &Arc<StdRwLock<Metrics>>, model: &str, elapsed: Duration) {
+    metrics.write().unwrap().tokenizer_load_seconds.insert(model.to_string(), elapsed.as_secs_f64());
+}
+
+fn _escape(label_value: &str) -> String {
+    label_value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn _header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn render(
+    metrics: &Metrics,
+    storage: &telemetry_structs::Storage,
+    cache: &CompletionCache,
+) -> String {
+    let mut out = String::new();
+    let mut sorted_keys: Vec<&(String, u16)> = metrics.http_requests.keys().collect();
+    sorted_keys.sort();
+    _header(&mut out, "refact_http_requests_total", "counter", "HTTP requests by route and status.");
+    for key in sorted_keys {
+        let _ = writeln!(out, "refact_http_requests_total{{route=\"{}\",status=\"{}\"}} {}", _escape(&key.0), key.1, metrics.http_requests[key]);
+    }
+    _header(&mut out, "refact_http_request_duration_seconds", "histogram", "HTTP request latency by route.");
+    let mut routes: Vec<&String> = metrics.http_latency.keys().collect();
+    routes.sort();
+    for route in routes {
+        metrics.http_latency[route].render(&mut out, "refact_http_request_duration_seconds", &format!("route=\"{}\"", _escape(route)));
+    }
+    _header(&mut out, "refact_upstream_request_duration_seconds", "histogram", "Model call latency, until the last streamed token.");
+    let mut models: Vec<&String> = metrics.upstream_latency.keys().collect();
+    models.sort();
+    for model in models {
+        metrics.upstream_latency[model].render(&mut out, "refact_upstream_request_duration_seconds", &format!("model=\"{}\"", _escape(model)));
+    }
+    _header(&mut out, "refact_upstream_errors_total", "counter", "Failed model calls.");
+    let mut models: Vec<&String> = metrics.upstream_errors.keys().collect();
+    models.sort();
+    for model in models {
+        let _ = writeln!(out, "refact_upstream_errors_total{{model=\"{}\"}} {}", _escape(model), metrics.upstream_errors[model]);
+    }
+    _header(&mut out, "refact_completion_cache_hits_total", "counter", "Code completions served from cache.");
+    let _ = writeln!(out, "refact_completion_cache_hits_total {}", cache.hits);
+    _header(&mut out, "refact_completion_cache_misses_total", "counter", "Code completions not found in cache.");
+    let _ = writeln!(out, "refact_completion_cache_misses_total {}", cache.misses);
+    _header(&mut out, "refact_tokenizer_load_seconds", "gauge", "Time it took to download and load the tokenizer.");
+    let mut models: Vec<&String> = metrics.tokenizer_load_seconds.keys().collect();
+    models.sort();
+    for model in models {
+        let _ = writeln!(out, "refact_tokenizer_load_seconds{{model=\"{}\"}} {}", _escape(model), metrics.tokenizer_load_seconds[model]);
+    }
+    _header(&mut out, "refact_telemetry_storage_records", "gauge", "Records in the in-memory telemetry storage, not yet compressed to disk.");
+    for (kind, len) in [
+        ("network", storage.tele_net.len()),
+        ("robot_human", storage.tele_robot_human.len()),
+        ("snippets", storage.tele_snippets.len()),
+        ("snippet_data_accumulators", storage.snippet_data_accumulators.len()),
+    ] {
+        let _ = writeln!(out, "refact_telemetry_storage_records{{kind=\"{}\"}} {}", kind, len);
+    }
+    out
+}
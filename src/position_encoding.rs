@@ -0,0 +1,163 @@
+use ropey::{Rope, RopeSlice};
+
+// Internally cursor positions count unicode chars (same as "utf-32"), that's what ropey slices by.
+// Clients speak "utf-16" by default (LSP spec), or negotiate "utf-8" / "utf-32".
+pub const UTF8: &str = "utf-8";
+pub const UTF16: &str = "utf-16";
+pub const UTF32: &str = "utf-32";
+pub const SUPPORTED: [&str; 3] = [UTF8, UTF16, UTF32];
+
+
+fn line_without_eol(line: RopeSlice) -> RopeSlice {
+    let mut len = line.len_chars();
+    while len > 0 && (line.char(len - 1) == '\n' || line.char(len - 1) == '\r') {
+        len -= 1;
+    }
+    line.slice(..len)
+}
+
+pub fn units_to_char_in_line(
+    line: RopeSlice,
+    units: usize,
+    encoding: &str,
+) -> usize {
+    // Positions past the end of line fall back to the end of line, as the LSP spec says
+    let line = line_without_eol(line);
+    match encoding {
+        UTF8 => line.byte_to_char(units.min(line.len_bytes())),
+        UTF16 => line.utf16_cu_to_char(units.min(line.len_utf16_cu())),
+        _ => units.min(line.len_chars()),
+    }
+}
+
+pub fn char_to_units_in_line(
+    line: RopeSlice,
+    char_idx: usize,
+    encoding: &str,
+) -> usize {
+    let char_idx = char_idx.min(line.len_chars());
+    match encoding {
+        UTF8 => line.char_to_byte(char_idx),
+        UTF16 => line.char_to_utf16_cu(char_idx),
+        _ => char_idx,
+    }
+}
+
+pub fn position_to_char_idx(
+    rope: &Rope,
+    line: usize,
+    units: usize,
+    encoding: &str,
+) -> usize {
+    if line >= rope.len_lines() {
+        return rope.len_chars();
+    }
+    rope.line_to_char(line) + units_to_char_in_line(rope.line(line), units, encoding)
+}
+
+pub fn char_idx_to_position(
+    rope: &Rope,
+    char_idx: usize,
+    encoding: &str,
+) -> (usize, usize) {
+    let char_idx = char_idx.min(rope.len_chars());
+    let line = rope.char_to_line(char_idx);
+    let units = char_to_units_in_line(rope.line(line), char_idx - rope.line_to_char(line), encoding);
+    (line, units)
+}
+
+pub fn cursor_to_chars(
+    text: &str,
+    line: i32,
+    units: i32,
+    encoding: &str,
+) -> Result<i32, String> {
+    if !encoding.is_empty() && !SUPPORTED.contains(&encoding) {
+        return Err(format!("position encoding \"{}\" is not supported, use one of {:?}", encoding, SUPPORTED));
+    }
+    let rope = Rope::from_str(text);
+    if line < 0 || line as usize >= rope.len_lines() {
+        return Err(format!("cursor line {} is outside of the file, it has {} lines", line, rope.len_lines()));
+    }
+    if units < 0 {
+        return Err(format!("cursor character {} is negative", units));
+    }
+    Ok(units_to_char_in_line(rope.line(line as usize), units as usize, encoding) as i32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "a😀b" — the emoji is 2 UTF-16 code units and 4 UTF-8 bytes, "日本" is 1 unit / 3 bytes per char
+    const TEXT: &str = "a😀b\n日本x\r\nend";
+
+    #[test]
+    fn test_units_to_char_utf16_surrogate_pair() {
+        let rope = Rope::from_str(TEXT);
+        assert_eq!(units_to_char_in_line(rope.line(0), 0, UTF16), 0);
+        assert_eq!(units_to_char_in_line(rope.line(0), 1, UTF16), 1);
+        assert_eq!(units_to_char_in_line(rope.line(0), 3, UTF16), 2);
+        assert_eq!(units_to_char_in_line(rope.line(0), 4, UTF16), 3);
+    }
+
+    #[test]
+    fn test_units_to_char_utf8_and_utf32() {
+        let rope = Rope::from_str(TEXT);
+        assert_eq!(units_to_char_in_line(rope.line(0), 5, UTF8), 2);
+        assert_eq!(units_to_char_in_line(rope.line(1), 6, UTF8), 2);
+        assert_eq!(units_to_char_in_line(rope.line(1), 2, UTF16), 2);
+        assert_eq!(units_to_char_in_line(rope.line(0), 2, UTF32), 2);
+    }
+
+    #[test]
+    fn test_units_past_end_of_line_clamp_before_eol() {
+        let rope = Rope::from_str(TEXT);
+        assert_eq!(units_to_char_in_line(rope.line(0), 100, UTF16), 3);
+        assert_eq!(units_to_char_in_line(rope.line(1), 100, UTF8), 3);
+        assert_eq!(units_to_char_in_line(rope.line(1), 100, UTF32), 3);
+    }
+
+    #[test]
+    fn test_char_to_units() {
+        let rope = Rope::from_str(TEXT);
+        assert_eq!(char_to_units_in_line(rope.line(0), 2, UTF16), 3);
+        assert_eq!(char_to_units_in_line(rope.line(0), 2, UTF8), 5);
+        assert_eq!(char_to_units_in_line(rope.line(1), 2, UTF8), 6);
+        assert_eq!(char_to_units_in_line(rope.line(1), 2, UTF32), 2);
+    }
+
+    #[test]
+    fn test_position_round_trip() {
+        let rope = Rope::from_str(TEXT);
+        for encoding in SUPPORTED {
+            for char_idx in 0..=rope.len_chars() {
+                // Between "\r" and "\n" isn't a position a client can send
+                if char_idx > 0 && rope.char(char_idx - 1) == '\r' {
+                    continue;
+                }
+                let (line, units) = char_idx_to_position(&rope, char_idx, encoding);
+                assert_eq!(position_to_char_idx(&rope, line, units, encoding), char_idx, "{} char_idx {}", encoding, char_idx);
+            }
+        }
+    }
+
+    #[test]
+    fn test_position_out_of_range() {
+        let rope = Rope::from_str(TEXT);
+        assert_eq!(position_to_char_idx(&rope, 100, 0, UTF16), rope.len_chars());
+        assert_eq!(char_idx_to_position(&rope, 1000, UTF16), (2, 3));
+    }
+
+    #[test]
+    fn test_cursor_to_chars() {
+        assert_eq!(cursor_to_chars(TEXT, 0, 3, UTF16), Ok(2));
+        assert_eq!(cursor_to_chars(TEXT, 1, 6, UTF8), Ok(2));
+        assert_eq!(cursor_to_chars(TEXT, 1, 2, ""), Ok(2));
+        assert!(cursor_to_chars(TEXT, 3, 0, UTF16).is_err());
+        assert!(cursor_to_chars(TEXT, -1, 0, UTF16).is_err());
+        assert!(cursor_to_chars(TEXT, 0, -1, UTF16).is_err());
+        assert!(cursor_to_chars(TEXT, 0, 0, "utf-7").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use tracing::{error, info};
+use std::collections::VecDeque;
 use std::sync::Arc;
 use tokio::sync::RwLock as ARwLock;
 use reqwest_eventsource::Event;
@@ -6,6 +7,7 @@ use serde_json::json;
 use futures::StreamExt;
 use async_stream::stream;
 use hyper::{Body, Response, StatusCode};
+use hyper::body::HttpBody;
 
 use crate::scratchpad_abstract::ScratchpadAbstract;
 use crate::forward_to_hf_endpoint;
@@ -16,6 +18,21 @@ use crate::telemetry::telemetry_structs;
 use crate::global_context::GlobalContext;
 
 
+struct UpstreamCallGuard {
+    // Futures here are dropped when the client goes away (HTTP disconnect, LSP $/cancelRequest), dropping
+    // the reqwest call or EventSource closes the upstream connection. This only makes it visible in logs.
+    scope: String,
+    done: bool,
+}
+
+impl Drop for UpstreamCallGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            info!("{} cancelled by client, upstream request dropped", self.scope);
+        }
+    }
+}
+
 pub async fn scratchpad_interaction_not_stream(
     global_context: Arc<ARwLock<GlobalContext>>,
     mut scratchpad: Box<dyn ScratchpadAbstract>,
@@ -27,12 +44,14 @@ pub async fn scratchpad_interaction_not_stream(
     parameters: &SamplingParameters,
 ) -> Result<Response<Body>, ScratchError> {
     let t2 = std::time::SystemTime::now();
-    let (endpoint_style, endpoint_template, endpoint_chat_passthrough, tele_storage) = {
+    let (endpoint_style, endpoint_template, endpoint_chat_passthrough, tele_storage, metrics) = {
         let cx = global_context.write().await;
         let caps = cx.caps.clone().unwrap();
         let caps_locked = caps.read().unwrap();
-        (caps_locked.endpoint_style.clone(), caps_locked.endpoint_template.clone(), caps_locked.endpoint_chat_passthrough.clone(), cx.telemetry.clone())
+        (caps_locked.endpoint_style.clone(), caps_locked.endpoint_template.clone(), caps_locked.endpoint_chat_passthrough.clone(), cx.telemetry.clone(), cx.metrics.clone())
     };
+    let _permit = _model_permit(global_context.clone(), &scope, &model_name).await?;
+    let mut guard = UpstreamCallGuard { scope: scope.clone(), done: false };
     let mut save_url: String = String::new();
     let model_says = if endpoint_style == "hf" {
         forward_to_hf_endpoint::forward_to_hf_style_endpoint(
@@ -55,7 +74,11 @@ pub async fn scratchpad_interaction_not_stream(
             &endpoint_chat_passthrough,
             &parameters,
         ).await
-    }.map_err(|e| {
+    };
+    guard.done = true;
+    crate::metrics::upstream_call(&metrics, &model_name, t2.elapsed().unwrap_or_default(), model_says.is_ok());
+    crate::global_context::status_model_call(global_context.clone(), model_says.as_ref().err().map(|e| format!("forward_to_endpoint: {}", e))).await;
+    let model_says = model_says.map_err(|e| {
         tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
                 save_url.clone(),
                 scope.clone(),
@@ -137,14 +160,18 @@ pub async fn scratchpad_interaction_stream(
     parameters: SamplingParameters,
 ) -> Result<Response<Body>, ScratchError> {
     let t1 = std::time::SystemTime::now();
+    let permit = _model_permit(global_context.clone(), &scope, &model_name).await?;
     let evstream = stream! {
+        let _permit = permit;
         let scratch: &mut Box<dyn ScratchpadAbstract> = &mut scratchpad;
-        let (endpoint_style, endpoint_template, endpoint_chat_passthrough, tele_storage) = {
+        let mut guard = UpstreamCallGuard { scope: scope.clone(), done: false };
+        let (endpoint_style, endpoint_template, endpoint_chat_passthrough, tele_storage, metrics) = {
             let cx = global_context.write().await;
             let caps = cx.caps.clone().unwrap();
             let caps_locked = caps.read().unwrap();
-            (caps_locked.endpoint_style.clone(), caps_locked.endpoint_template.clone(), caps_locked.endpoint_chat_passthrough.clone(), cx.telemetry.clone())
+            (caps_locked.endpoint_style.clone(), caps_locked.endpoint_template.clone(), caps_locked.endpoint_chat_passthrough.clone(), cx.telemetry.clone(), cx.metrics.clone())
         };
+        let metrics_model = model_name.clone();
         let mut save_url: String = String::new();
         loop {
             let event_source_maybe = if endpoint_style == "hf" {
@@ -180,6 +207,8 @@ pub async fn scratchpad_interaction_stream(
                         e_str.to_string(),
                     ));
                     error!(e_str);
+                    crate::metrics::upstream_call(&metrics, &metrics_model, t1.elapsed().unwrap_or_default(), false);
+                    crate::global_context::status_model_call(global_context.clone(), Some(e_str.clone())).await;
                     let value_str = serde_json::to_string(&json!({"detail": e_str})).unwrap();
                     yield Result::<_, String>::Ok(value_str);
                     break;
@@ -247,6 +276,8 @@ pub async fn scratchpad_interaction_stream(
                                 problem_str.clone(),
                             ));
                         }
+                        crate::metrics::upstream_call(&metrics, &metrics_model, t1.elapsed().unwrap_or_default(), false);
+                        crate::global_context::status_model_call(global_context.clone(), Some(problem_str.clone())).await;
                         yield Result::<_, String>::Ok(serde_json::to_string(&json!({"detail": problem_str})).unwrap());
                         problem_reported = true;
                         event_source.close();
@@ -255,6 +286,7 @@ pub async fn scratchpad_interaction_stream(
                 }
             }
             if problem_reported {
+                guard.done = true;
                 return;
             } else if !finished {
                 let mut value: serde_json::Value;
@@ -265,8 +297,11 @@ pub async fn scratchpad_interaction_stream(
                 info!("yield final: {:?}", value_str);
                 yield Result::<_, String>::Ok(value_str);
             }
+            crate::metrics::upstream_call(&metrics, &metrics_model, t1.elapsed().unwrap_or_default(), true);
+            crate::global_context::status_model_call(global_context.clone(), None).await;
             break;
         }
+        guard.done = true;
         info!("yield: [DONE]");
         yield Result::<_, String>::Ok("data: [DONE]\n\n".to_string());
         tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
@@ -284,6 +319,26 @@ pub async fn scratchpad_interaction_stream(
     return Ok(response);
 }
 
+async fn _model_permit(
+    global_context: Arc<ARwLock<GlobalContext>>,
+    scope: &str,
+    model_name: &str,
+) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ScratchError> {
+    let (limits, tele_storage, endpoint_template) = {
+        let cx = global_context.read().await;
+        let endpoint_template = cx.caps.as_ref().map(|caps| caps.read().unwrap().endpoint_template.clone()).unwrap_or_default();
+        (cx.limits.clone(), cx.telemetry.clone(), endpoint_template)
+    };
+    crate::limits::model_permit(&limits, model_name).map_err(|max_concurrent| {
+        crate::limits::too_many_requests(
+            &tele_storage,
+            &endpoint_template.replace("$MODEL", model_name),
+            scope,
+            format!("model {} already has {} requests running, try again later", model_name, max_concurrent),
+        )
+    })
+}
+
 fn _push_streaming_json_into_scratchpad(
     scratch: &mut Box<dyn ScratchpadAbstract>,
     json: &serde_json::Value,
@@ -324,6 +379,93 @@ fn _push_streaming_json_into_scratchpad(
     }
 }
 
+pub struct RestreamReader {
+    // Reads back what scratchpad_interaction_stream produces: "data: {json}\n\n" events, errors come without
+    // "data:", and chunks of the body don't have to match events.
+    body: Body,
+    buf: Vec<u8>,
+    events: VecDeque<String>,
+    finished: bool,
+}
+
+impl RestreamReader {
+    pub fn new(body: Body) -> Self {
+        RestreamReader { body, buf: Vec::new(), events: VecDeque::new(), finished: false }
+    }
+
+    // None at the end of the stream, Err for {"detail": ...} errors and broken events
+    pub async fn next(&mut self) -> Option<Result<serde_json::Value, String>> {
+        loop {
+            if let Some(data) = self.events.pop_front() {
+                if data.starts_with("[DONE]") {
+                    continue;
+                }
+                return Some(match serde_json::from_str::<serde_json::Value>(&data) {
+                    Ok(value) => match value.get("detail") {
+                        Some(detail) => Err(detail.as_str().map(|d| d.to_string()).unwrap_or(detail.to_string())),
+                        None => Ok(value),
+                    },
+                    Err(e) => Err(format!("{}: {:?}", e, data)),
+                });
+            }
+            if self.finished {
+                return None;
+            }
+            match self.body.data().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    self.finished = true;
+                    return Some(Err(format!("{}", e)));
+                }
+                None => {
+                    self.finished = true;
+                    self.buf.extend_from_slice(b"\n\n");
+                }
+            }
+            // Split on bytes, a chunk can end in the middle of a UTF-8 character
+            while let Some(pos) = self.buf.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = self.buf.drain(..pos + 2).collect();
+                let data = String::from_utf8_lossy(&event).trim().trim_start_matches("data:").trim().to_string();
+                if !data.is_empty() {
+                    self.events.push_back(data);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatAnswer {
+    // Deltas collected into one message
+    pub role: String,
+    pub content: String,
+    pub finish_reason: String,
+    pub model: String,
+}
+
+impl ChatAnswer {
+    pub fn new(model: &str) -> Self {
+        ChatAnswer { role: "assistant".to_string(), content: String::new(), finish_reason: String::new(), model: model.to_string() }
+    }
+
+    pub fn add_chunk(&mut self, value: &serde_json::Value) {
+        if let Some(choice0) = value.get("choices").and_then(|c| c.get(0)) {
+            if let Some(delta) = choice0.get("delta") {
+                self.content.push_str(delta.get("content").and_then(|x| x.as_str()).unwrap_or(""));
+                if let Some(role) = delta.get("role").and_then(|x| x.as_str()).filter(|r| !r.is_empty()) {
+                    self.role = role.to_string();
+                }
+            }
+            if let Some(finish_reason) = choice0.get("finish_reason").and_then(|x| x.as_str()) {
+                self.finish_reason = finish_reason.to_string();
+            }
+        }
+        if let Some(model) = value.get("model").and_then(|x| x.as_str()) {
+            self.model = model.to_string();
+        }
+    }
+}
+
 pub async fn cached_not_stream(
     cached_json_value: &serde_json::Value,
 ) -> Result<Response<Body>, ScratchError> {
@@ -96,13 +96,16 @@ impl ScratchpadAbstract for SingleFileFIM {
         let text = Rope::from_str(&*source);
 
         let pos = &self.post.inputs.cursor;
+        if pos.line < 0 || pos.line as usize >= text.len_lines() {
+            return Err(format!("cursor line {} is outside of the file", pos.line));
+        }
         let mut before_iter = text.lines_at(pos.line as usize).reversed();
         let mut after_iter = text.lines_at(pos.line as usize + 1);
 
         let mut before_line = before_iter.next();
 
         let cursor_line1: String;
-        let col = pos.character as usize;
+        let col = (pos.character.max(0) as usize).min(text.line(pos.line as usize).len_chars());
         cursor_line1 = text.line(pos.line as usize).slice(0..col).to_string();
         // UNFINISHED LI|
 
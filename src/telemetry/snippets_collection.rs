@@ -16,6 +16,7 @@ use crate::telemetry::utils;
 // How it works:
 // 1. Rust returns {"snippet_telemetry_id":101,"choices":[{"code_completion":"\n    return \"Hello World!\"\n"}] ...}
 // 2. IDE detects accept, sends /v1/completion-accepted with {"snippet_telemetry_id":101}
+//    (or LSP refact/snippetAccepted, refact/snippetRejected if the user dismissed it)
 // 3. LSP looks at file changes
 // 4. Changes are translated to base telemetry counters
 
@@ -45,15 +46,19 @@ fn snippet_register(
 ) -> u64 {
     let mut storage_locked = ss.storage_arc.write().unwrap();
     let snippet_telemetry_id = storage_locked.tele_snippet_next_id;
+    // Other open files are only context for the model, they don't belong in snippet telemetry
+    let mut inputs = ss.post.inputs.clone();
+    inputs.sources.retain(|file, _| *file == inputs.cursor.file);
     let snip = telemetry_structs::SnippetTracker {
         snippet_telemetry_id,
         model: ss.post.model.clone(),
-        inputs: ss.post.inputs.clone(),
+        inputs,
         grey_text: grey_text.clone(),
         corrected_by_user: "".to_string(),
         remaining_percentage: -1.,
         created_ts: chrono::Local::now().timestamp(),
         accepted_ts: 0,
+        rejected_ts: 0,
         finished_ts: 0,
     };
     storage_locked.tele_snippet_next_id += 1;
@@ -87,11 +92,30 @@ pub async fn snippet_accepted(
     let snip = storage_locked.tele_snippets.iter_mut().find(|s| s.snippet_telemetry_id == snippet_telemetry_id);
     if let Some(snip) = snip {
         snip.accepted_ts = chrono::Local::now().timestamp();
+        snip.rejected_ts = 0;
         return true;
     }
     return false;
 }
 
+pub async fn snippet_rejected(
+    gcx: Arc<ARwLock<global_context::GlobalContext>>,
+    snippet_telemetry_id: u64,
+) -> bool {
+    // Explicit dismiss, as opposed to a snippet that silently times out. Accept wins if both arrive.
+    let tele_storage_arc = gcx.read().await.telemetry.clone();
+    let mut storage_locked = tele_storage_arc.write().unwrap();
+    let snip = storage_locked.tele_snippets.iter_mut().find(|s| s.snippet_telemetry_id == snippet_telemetry_id);
+    if let Some(snip) = snip {
+        if snip.accepted_ts != 0 {
+            return false;
+        }
+        snip.rejected_ts = chrono::Local::now().timestamp();
+        return true;
+    }
+    false
+}
+
 
 pub async fn sources_changed(
     gcx: Arc<ARwLock<global_context::GlobalContext>>,
@@ -140,3 +164,30 @@ pub async fn sources_changed(
     }
     basic_comp_counters::on_file_text_changed(&mut storage_locked.snippet_data_accumulators, uri, text);
 }
+
+pub async fn sources_closed(
+    gcx: Arc<ARwLock<global_context::GlobalContext>>,
+    uri: &String,
+    text: &String,
+) {
+    // The document is gone, no more changes will come: finish accepted snippets with the score they have now
+    let tele_storage = gcx.read().await.telemetry.clone();
+    let mut storage_locked = tele_storage.write().unwrap();
+    let mut finished_snips = vec![];
+    for snip in storage_locked.tele_snippets.iter_mut() {
+        if snip.accepted_ts == 0 || snip.finished_ts > 0 || !uri.ends_with(&snip.inputs.cursor.file) {
+            continue;
+        }
+        if snip.remaining_percentage >= 0. {
+            snip.finished_ts = chrono::Local::now().timestamp();
+            finished_snips.push(snip.clone());
+        } else {
+            snip.accepted_ts = 0;  // that will cleanup and not send
+        }
+    }
+
+    for snip in finished_snips {
+        basic_robot_human::increase_counters_from_finished_snippet(&mut storage_locked.tele_robot_human, uri, text, &snip);
+        basic_comp_counters::create_data_accumulator_for_finished_snippet(&mut storage_locked.snippet_data_accumulators, uri, &snip);
+    }
+}
@@ -47,6 +47,11 @@ pub async fn send_finished_snippets(gcx: Arc<ARwLock<global_context::GlobalConte
                 }
                 continue;
             }
+            if snip.rejected_ts != 0 {
+                to_remove.push(idx);
+                snips_send.push(snip.clone());
+                continue;
+            }
             if snip.accepted_ts == 0 && snip.created_ts + SNIP_NOT_ACCEPTED_TIMEOUT_AFTER < now {
                 to_remove.push(idx);
                 continue;
@@ -57,6 +57,8 @@ pub struct SnippetTracker {
     pub remaining_percentage: f64,
     pub created_ts: i64,
     pub accepted_ts: i64,
+    #[serde(default)]
+    pub rejected_ts: i64,
     pub finished_ts: i64,
 }
 